@@ -1,10 +1,15 @@
 BITS 64
 
+; Shared by every run of the library, which is why backend::emulator_main runs one program at a time
 section .bss
     ret_addr: resq 1
     instruction_count: resq 1
     mem_read_callback: resq 1
     mem_write_callback: resq 1
+    io_context: resq 1
+    call_stack: resq 16
+    call_stack_pointer: resq 1
+    call_stack_depth: resq 1
 
 section .text
 global _main
@@ -40,6 +45,10 @@ __clear_zero:
     mov r15, 0
     jmp _sf2
 
+_fault:
+    mov rsp, [ret_addr]
+    ret
+
 _main:
     mov [ret_addr], rsp
     mov r12, rcx
@@ -48,4 +57,14 @@ _main:
     mov [mem_write_callback], r9
     mov rax, [rsp + 40]
     mov [instruction_count], rax
-    sub rsp, 8
\ No newline at end of file
+    mov rax, [rsp + 48]
+    mov [io_context], rax
+    sub rsp, 8
+    lea rax, [rel _rom_start]
+    lea rdx, [rel call_stack]
+    mov rcx, 16
+_init_call_stack:
+    mov [rdx + rcx*8 - 8], rax
+    loop _init_call_stack
+    mov qword [rel call_stack_pointer], 0
+    mov qword [rel call_stack_depth], 0
@@ -0,0 +1,80 @@
+BITS 64
+default rel
+
+section .note.GNU-stack noalloc noexec nowrite progbits
+
+; Shared by every run of the library, which is why backend::emulator_main runs one program at a time
+section .bss
+    ret_addr: resq 1
+    instruction_count: resq 1
+    mem_read_callback: resq 1
+    mem_write_callback: resq 1
+    io_context: resq 1
+    call_stack: resq 16
+    call_stack_pointer: resq 1
+    call_stack_depth: resq 1
+
+section .text
+global _main
+
+_set_flags:
+    jc _set_carry
+    jmp _clear_carry
+_sf1:
+    jz __set_zero
+    jmp __clear_zero
+_sf2:
+    ret
+
+_set_carry:
+    mov r14, 1
+    jmp _sf1
+
+_clear_carry:
+    mov r14, 0
+    jmp _sf1
+
+__set_zero:
+    mov r15, 1
+    jmp _sf2
+
+__clear_zero:
+    mov r15, 0
+    jmp _sf2
+
+_halt:
+    mov rsp, [ret_addr]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+
+_fault:
+    jmp _halt
+
+_main:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [ret_addr], rsp
+    mov r12, rdi
+    mov r13, rsi
+    mov [mem_read_callback], rdx
+    mov [mem_write_callback], rcx
+    mov [instruction_count], r8
+    mov [io_context], r9
+    sub rsp, 8
+    lea rax, [rel _rom_start]
+    lea rdx, [rel call_stack]
+    mov rcx, 16
+_init_call_stack:
+    mov [rdx + rcx*8 - 8], rax
+    loop _init_call_stack
+    mov qword [rel call_stack_pointer], 0
+    mov qword [rel call_stack_depth], 0
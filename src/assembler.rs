@@ -0,0 +1,379 @@
+use std::{collections::HashMap, fmt, fs, path::{Path, PathBuf}};
+
+use anyhow::{bail, Context};
+
+const OPCODES: [&str; 16] = ["nop", "hlt", "add", "sub", "nor", "and", "xor", "rsh", "ldi", "adi", "jmp", "brh", "cal", "ret", "lod", "str"];
+
+const PSEUDO_INSTRUCTIONS: [(&str, usize); 7] = [("cmp", 2), ("mov", 2), ("lsh", 2), ("inc", 1), ("dec", 1), ("not", 2), ("neg", 2)];
+
+const CONDITIONS: [[&str; 4]; 4] = [
+    ["eq", "ne", "ge", "lt"],
+    ["=", "!=", ">=", "<"],
+    ["z", "nz", "c", "nc"],
+    ["zero", "notzero", "carry", "notcarry"],
+];
+
+const PORTS: [&str; 16] = [
+    "pixel_x", "pixel_y", "draw_pixel", "clear_pixel", "load_pixel", "buffer_screen", "clear_screen_buffer", "write_char",
+    "buffer_chars", "clear_chars_buffer", "show_number", "clear_number", "signed_mode", "unsigned_mode", "rng", "controller_input"
+];
+
+pub const CHARACTERS: [char; 30] = [
+    ' ', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w',
+    'x', 'y', 'z', '.', '!', '?'
+];
+
+#[derive(Debug)]
+pub struct AssemblerError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file.display(), self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+/// Machine code produced by the assembler, along with the labels defined in the source
+#[derive(Debug)]
+pub struct Assembly {
+    pub program: Vec<u16>,
+    /// Label names (including the leading `.`) and the addresses they refer to, in order of definition
+    pub labels: Vec<(String, u16)>,
+    /// The (1-based) source line of each instruction
+    pub lines: Vec<usize>,
+}
+
+/// A whitespace separated word of a source line, along with its (1-based) position
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+/// Assembles BatPU-2 assembly into machine code words, accepting the same syntax as the reference Python assembler
+pub fn assemble(src: &str, file: &Path) -> Result<Assembly, AssemblerError> {
+    let lines = tokenize(src);
+    let mut symbols = builtin_symbols();
+
+    let error = |token: &Token, message: String| AssemblerError {
+        file: file.to_path_buf(),
+        line: token.line,
+        column: token.column,
+        message,
+    };
+
+    // Resolve definitions and labels
+    let mut labels = Vec::new();
+    let mut pc = 0;
+    for words in lines.iter() {
+        if words[0].text == "define" {
+            if words.len() != 3 {
+                return Err(error(&words[0], "Expected `define <name> <value>`".into()));
+            }
+            let value = parse_number(&words[2].text)
+                .ok_or_else(|| error(&words[2], format!("Invalid number `{}`", words[2].text)))?;
+            symbols.insert(words[1].text.clone(), value);
+        } else if is_label(&words[0].text) {
+            symbols.insert(words[0].text.clone(), pc);
+            labels.push((words[0].text.clone(), pc as u16));
+            if words.len() > 1 {
+                pc += 1;
+            }
+        } else {
+            pc += 1;
+        }
+    }
+
+    let mut output = Vec::new();
+    let mut source_lines = Vec::new();
+    for words in lines.iter() {
+        let mut words = words.as_slice();
+        if is_label(&words[0].text) {
+            words = &words[1..];
+        }
+        if words.is_empty() || words[0].text == "define" {
+            continue;
+        }
+
+        let words = expand_pseudo_instruction(words);
+        let mnemonic = &words[0];
+
+        let Some(opcode) = OPCODES.iter().position(|&op| op == mnemonic.text) else {
+            if let Some((_, expected_operands)) = PSEUDO_INSTRUCTIONS.iter().find(|(op, _)| *op == mnemonic.text) {
+                return Err(error(mnemonic, format!("`{}` expects {expected_operands} operands, found {}", mnemonic.text, words.len() - 1)));
+            }
+            return Err(error(mnemonic, format!("Unknown instruction `{}`", mnemonic.text)));
+        };
+
+        let expected_operands = match opcode {
+            0 | 1 | 13 => 0,
+            10 | 12 => 1,
+            7 | 8 | 9 | 11 => 2,
+            _ => 3,
+        };
+        if words.len() - 1 != expected_operands {
+            return Err(error(mnemonic, format!("`{}` expects {expected_operands} operands, found {}", mnemonic.text, words.len() - 1)));
+        }
+
+        let operands = words[1..].iter()
+            .map(|word| resolve(word, &symbols).ok_or_else(|| error(word, format!("Unknown symbol `{}`", word.text))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let check = |index: usize, range: std::ops::RangeInclusive<i32>, kind: &str| {
+            if range.contains(&operands[index]) {
+                Ok(operands[index])
+            } else {
+                Err(error(&words[index + 1], format!("{kind} `{}` out of range [{}, {}]", words[index + 1].text, range.start(), range.end())))
+            }
+        };
+
+        let mut machine_code = (opcode as u16) << 12;
+        match opcode {
+            // add, sub, nor, and, xor
+            2..=6 => {
+                machine_code |= (check(0, 0..=15, "Register")? as u16) << 8;
+                machine_code |= (check(1, 0..=15, "Register")? as u16) << 4;
+                machine_code |= check(2, 0..=15, "Register")? as u16;
+            },
+            // rsh
+            7 => {
+                machine_code |= (check(0, 0..=15, "Register")? as u16) << 8;
+                machine_code |= check(1, 0..=15, "Register")? as u16;
+            },
+            // ldi, adi
+            8 | 9 => {
+                machine_code |= (check(0, 0..=15, "Register")? as u16) << 8;
+                machine_code |= (check(1, -128..=255, "Immediate")? as u16) & 0xFF;
+            },
+            // jmp, cal
+            10 | 12 => machine_code |= check(0, 0..=1023, "Address")? as u16,
+            // brh
+            11 => {
+                machine_code |= (check(0, 0..=3, "Condition")? as u16) << 10;
+                machine_code |= check(1, 0..=1023, "Address")? as u16;
+            },
+            // lod, str
+            14 | 15 => {
+                machine_code |= (check(0, 0..=15, "Register")? as u16) << 8;
+                machine_code |= (check(1, 0..=15, "Register")? as u16) << 4;
+                machine_code |= (check(2, -8..=7, "Offset")? as u16) & 0xF;
+            },
+            _ => {}
+        }
+
+        output.push(machine_code);
+        source_lines.push(mnemonic.line);
+    }
+
+    Ok(Assembly {
+        program: output,
+        labels,
+        lines: source_lines,
+    })
+}
+
+fn tokenize(src: &str) -> Vec<Vec<Token>> {
+    src.lines()
+        .enumerate()
+        .filter_map(|(line_index, line)| {
+            let code = line.split(['/', ';', '#']).next().unwrap_or("");
+
+            let chars = code.chars().collect::<Vec<_>>();
+            let mut words = Vec::new();
+            let mut start = 0;
+            while start < chars.len() {
+                if chars[start].is_whitespace() {
+                    start += 1;
+                    continue;
+                }
+
+                // Character literals are single words even when the character is a space, like `" "`
+                let end = match chars[start..] {
+                    [quote @ ('"' | '\''), _, close, ..] if close == quote => start + 3,
+                    _ => chars[start..].iter().position(|c| c.is_whitespace()).map_or(chars.len(), |len| start + len),
+                };
+                words.push(Token {
+                    text: chars[start..end].iter().collect::<String>().to_lowercase(),
+                    line: line_index + 1,
+                    column: start + 1,
+                });
+                start = end;
+            }
+
+            (!words.is_empty()).then_some(words)
+        })
+        .collect()
+}
+
+fn builtin_symbols() -> HashMap<String, i32> {
+    let mut symbols = HashMap::new();
+
+    for (i, register) in (0..16).map(|i| format!("r{i}")).enumerate() {
+        symbols.insert(register, i as i32);
+    }
+    for names in CONDITIONS {
+        for (i, name) in names.iter().enumerate() {
+            symbols.insert(name.to_string(), i as i32);
+        }
+    }
+    for (i, port) in PORTS.iter().enumerate() {
+        symbols.insert(port.to_string(), i as i32 + 240);
+    }
+    for (i, c) in CHARACTERS.iter().enumerate() {
+        symbols.insert(format!("\"{c}\""), i as i32);
+        symbols.insert(format!("'{c}'"), i as i32);
+    }
+
+    symbols
+}
+
+/// Loads label names for a program from a symbol file with one `<address> <name>` pair per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn load_symbols(path: &Path) -> anyhow::Result<Vec<(String, u16)>> {
+    let src = fs::read_to_string(path).with_context(|| format!("Failed to read symbol file {}", path.display()))?;
+    parse_symbols(&src).with_context(|| format!("Invalid symbol file {}", path.display()))
+}
+
+pub fn parse_symbols(src: &str) -> anyhow::Result<Vec<(String, u16)>> {
+    let mut symbols = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((addr, name)) = line.split_once(char::is_whitespace) else {
+            bail!("line {}: expected `<address> <name>`", i + 1);
+        };
+        let Some(addr) = parse_number(addr).and_then(|addr| u16::try_from(addr).ok()) else {
+            bail!("line {}: invalid address `{addr}`", i + 1);
+        };
+        symbols.push((label_name(name.trim()), addr));
+    }
+
+    Ok(symbols)
+}
+
+fn is_label(word: &str) -> bool {
+    word.starts_with('.')
+}
+
+/// A label name the way the assembler writes it: lowercase and starting with `.`
+pub fn label_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    if is_label(&name) { name } else { format!(".{name}") }
+}
+
+fn expand_pseudo_instruction(words: &[Token]) -> Vec<Token> {
+    let literal = |text: &str| Token { text: text.into(), ..words[0].clone() };
+    let operand = |i: usize| words[i].clone();
+
+    match (words[0].text.as_str(), words.len()) {
+        ("cmp", 3) => vec![literal("sub"), operand(1), operand(2), literal("r0")],
+        ("mov", 3) => vec![literal("add"), operand(1), literal("r0"), operand(2)],
+        ("lsh", 3) => vec![literal("add"), operand(1), operand(1), operand(2)],
+        ("inc", 2) => vec![literal("adi"), operand(1), literal("1")],
+        ("dec", 2) => vec![literal("adi"), operand(1), literal("-1")],
+        ("not", 3) => vec![literal("nor"), operand(1), literal("r0"), operand(2)],
+        ("neg", 3) => vec![literal("sub"), literal("r0"), operand(1), operand(2)],
+        // lod and str default to an offset of 0
+        ("lod" | "str", 3) => vec![operand(0), operand(1), operand(2), literal("0")],
+        _ => words.to_vec(),
+    }
+}
+
+fn resolve(word: &Token, symbols: &HashMap<String, i32>) -> Option<i32> {
+    if word.text.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        parse_number(&word.text)
+    } else {
+        symbols.get(&word.text).copied()
+    }
+}
+
+/// Parses a decimal, `0x` hex, `0b` binary or `0o` octal number with an optional sign
+pub fn parse_number(text: &str) -> Option<i32> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2).ok()?
+    } else if let Some(oct) = digits.strip_prefix("0o") {
+        i32::from_str_radix(oct, 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble_src(src: &str) -> Vec<u16> {
+        assemble(src, Path::new("test.as")).unwrap().program
+    }
+
+    fn error(src: &str) -> (usize, usize, String) {
+        let err = assemble(src, Path::new("test.as")).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn character_literals() {
+        assert_eq!(assemble_src("ldi r1 \" \""), [0x8100]);
+        assert_eq!(assemble_src("ldi r1 ' '"), [0x8100]);
+        assert_eq!(assemble_src("ldi r1 \"a\"\nldi r2 '?'"), [0x8101, 0x821D]);
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let src = "cmp r1 r2\nmov r1 r2\nlsh r1 r2\ninc r3\ndec r3\nnot r1 r2\nneg r1 r2\nlod r1 r2\nstr r1 r2 -1";
+        assert_eq!(assemble_src(src), [0x3120, 0x2102, 0x2112, 0x9301, 0x93FF, 0x4102, 0x3012, 0xE120, 0xF12F]);
+    }
+
+    #[test]
+    fn defines_ports_and_conditions() {
+        assert_eq!(assemble_src("define x 5\nldi r1 x\nldi r2 0x10"), [0x8105, 0x8210]);
+        assert_eq!(assemble_src("ldi r1 controller_input\nbrh ge 3\nbrh notzero 3"), [0x81FF, 0xB803, 0xB403]);
+    }
+
+    #[test]
+    fn labels() {
+        let assembly = assemble(".start jmp .end\nnop\n.end\nhlt // done\n", Path::new("test.as")).unwrap();
+        assert_eq!(assembly.program, [0xA002, 0x0000, 0x1000]);
+        assert_eq!(assembly.labels, [(".start".to_string(), 0), (".end".to_string(), 2)]);
+        assert_eq!(assembly.lines, [1, 2, 4]);
+    }
+
+    #[test]
+    fn range_errors() {
+        assert_eq!(error("nop\n  ldi r1 256"), (2, 10, "Immediate `256` out of range [-128, 255]".to_string()));
+        assert_eq!(error("adi r1 -129"), (1, 8, "Immediate `-129` out of range [-128, 255]".to_string()));
+        assert_eq!(error("lod r1 r2 8"), (1, 11, "Offset `8` out of range [-8, 7]".to_string()));
+        assert_eq!(error("str r1 r2 -9"), (1, 11, "Offset `-9` out of range [-8, 7]".to_string()));
+        assert_eq!(error("jmp 1024"), (1, 5, "Address `1024` out of range [0, 1023]".to_string()));
+        assert_eq!(error("add 16 r1 r2"), (1, 5, "Register `16` out of range [0, 15]".to_string()));
+        assert_eq!(error("add r16 r1 r2"), (1, 5, "Unknown symbol `r16`".to_string()));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("\n\n   foo r1"), (3, 4, "Unknown instruction `foo`".to_string()));
+        assert_eq!(error("inc"), (1, 1, "`inc` expects 1 operands, found 0".to_string()));
+        assert_eq!(error("ldi r1 2 3"), (1, 1, "`ldi` expects 2 operands, found 3".to_string()));
+        assert_eq!(error("ldi r1 .nowhere"), (1, 8, "Unknown symbol `.nowhere`".to_string()));
+    }
+}
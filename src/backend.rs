@@ -0,0 +1,215 @@
+use std::{fs, process::Command, ptr, sync::{Mutex, PoisonError}, thread, time::{Duration, Instant}};
+
+use anyhow::Result;
+
+use crate::{
+    interface::{self, IoBus, StateRequest},
+    interpreter::{ExecutionOptions, Fault},
+    jit::JitProgram,
+    transpiler::{Instruction, Target},
+    Emulator,
+};
+
+/// Outcome of running a program on one of the backends
+pub struct RunStats {
+    pub instruction_count: usize,
+    pub time: Duration,
+    pub fault: Option<Fault>,
+}
+
+/// Held while a native build runs, see [`emulator_main`]
+static NATIVE_RUN: Mutex<()> = Mutex::new(());
+
+/// Runs the native build `temp/{name}` produced by [`compile_asm`].
+///
+/// Native builds keep the call stack and the bus pointer for the memory handlers in globals of the loaded library,
+/// so only one native program runs at a time in a process; further calls wait until it has finished.
+pub fn emulator_main(name: &str, instructions: &[Instruction], iterations: usize, target: Target, io: &mut IoBus) -> RunStats {
+    let _running = NATIVE_RUN.lock().unwrap_or_else(PoisonError::into_inner);
+    unsafe {
+        let lib = libloading::Library::new(format!("temp/{name}.{}", target.library_extension())).unwrap();
+        let main: libloading::Symbol<CompiledMain> = lib.get(b"_main").unwrap();
+        run_compiled(*main, instructions, iterations, io)
+    }
+}
+
+pub fn jit_main(instructions: &[Instruction], count_instructions: bool, options: ExecutionOptions, iterations: usize, io: &mut IoBus) -> RunStats {
+    let compiled = JitProgram::compile(instructions, count_instructions, options);
+    run_compiled(compiled.main(), instructions, iterations, io)
+}
+
+pub fn run_compiled(main: CompiledMain, instructions: &[Instruction], iterations: usize, io: &mut IoBus) -> RunStats {
+    let mut memory: [u8; 256] = [0; 256];
+    let mut registers: [u8; 16] = [0; 16];
+
+    let start_count = io.state.instruction_count;
+    let mut fault = None;
+
+    let execution_time;
+    unsafe {
+        let mem_ptr = memory.as_mut_ptr();
+        let reg_ptr = registers.as_mut_ptr();
+        let io_ptr: *mut IoBus = io;
+        // The program counts straight into the bus so the devices can timestamp events
+        let counter = ptr::addr_of_mut!((*io_ptr).state.instruction_count);
+        let start_time = Instant::now();
+        #[allow(unused_assignments)]
+        for _ in 0..iterations {
+            memory = [0; 256];
+            registers = [0; 16];
+            (*io_ptr).reset();
+            let status = main(mem_ptr, reg_ptr, interface::on_mem_read, interface::on_mem_write, counter, io_ptr);
+
+            fault = Fault::from_status(status, instructions);
+            if fault.is_some() {
+                break;
+            }
+        }
+        execution_time = start_time.elapsed();
+    }
+
+    RunStats {
+        instruction_count: io.state.instruction_count - start_count,
+        time: execution_time,
+        fault,
+    }
+}
+
+/// Instructions the interpreter executes between checks for save state and rewind requests from the UI
+const STATE_REQUEST_INTERVAL: usize = 4096;
+
+/// How long each frame is shown for while rewinding
+const REWIND_FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// How far a single rewind step goes back if the screen hasn't been pushed in that time
+const MAX_REWIND_FRAME: usize = 1_000_000;
+
+/// Runs `emulator` from its current state, such as a loaded save state, resetting it between iterations.
+/// Save, load and rewind requests from the UI are carried out while it runs, and it is paced to the bus's throttle.
+pub fn interpreter_main(emulator: &mut Emulator, iterations: usize) -> RunStats {
+    let start_time = Instant::now();
+    for iteration in 0..iterations {
+        if iteration > 0 {
+            emulator.reset();
+        }
+
+        loop {
+            handle_state_request(emulator);
+            let (rewinding, unthrottled) = {
+                let panel = emulator.io().state.panel.lock().unwrap();
+                (panel.rewinding, panel.unthrottled)
+            };
+            if rewinding {
+                rewind_frame(emulator);
+                continue;
+            }
+
+            // Throttled programs run in short slices so they can be paced smoothly
+            let slice = match &emulator.io().state.throttle {
+                Some(throttle) if !unthrottled => throttle.slice(STATE_REQUEST_INTERVAL),
+                _ => STATE_REQUEST_INTERVAL,
+            };
+            if emulator.run_for(slice) < slice {
+                break;
+            }
+
+            let count = emulator.instruction_count();
+            let state = &mut emulator.io_mut().state;
+            state.instruction_count = count;
+            state.pace();
+        }
+
+        if emulator.fault().is_some() {
+            break;
+        }
+    }
+
+    RunStats {
+        instruction_count: emulator.instruction_count(),
+        time: start_time.elapsed(),
+        fault: emulator.fault(),
+    }
+}
+
+/// Steps back one frame while the UI's rewind key is held, pausing the program
+fn rewind_frame(emulator: &mut Emulator) {
+    let status = if !emulator.rewind_enabled() {
+        "Rewinding is disabled".to_string()
+    } else if emulator.rewind_frame(MAX_REWIND_FRAME) {
+        format!("Rewound to instruction {}", emulator.instruction_count())
+    } else {
+        "Reached the start of the rewind history".to_string()
+    };
+    emulator.io().state.panel.lock().unwrap().status = Some(status);
+    thread::sleep(REWIND_FRAME_TIME);
+}
+
+fn handle_state_request(emulator: &mut Emulator) {
+    let request = emulator.io().state.panel.lock().unwrap().state_request.take();
+    let result = match &request {
+        Some(StateRequest::Save(path)) => emulator.save_state(path).map(|()| format!("Saved state to {}", path.display())),
+        Some(StateRequest::Load(path)) => emulator.load_state(path).map(|()| format!("Loaded state from {}", path.display())),
+        None => return,
+    };
+
+    emulator.io().state.panel.lock().unwrap().status = Some(result.unwrap_or_else(|err| format!("Error: {err:#}")));
+}
+
+pub fn nasm_available() -> bool {
+    Command::new("nasm")
+        .arg("-v")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Assembles and links `src` into `temp/{name}`. Processes running at the same time need different names.
+pub fn compile_asm(src: &str, name: &str, target: Target) -> Result<()> {
+    fs::write(format!("temp/{name}.asm"), src).unwrap();
+
+    let (format, object) = match target {
+        Target::Windows => ("win64", format!("temp/{name}.obj")),
+        Target::Linux => ("elf64", format!("temp/{name}.o")),
+    };
+
+    if !Command::new("nasm")
+        .arg("-f")
+        .arg(format)
+        .arg(format!("temp/{name}.asm"))
+        .arg("-O0")
+        .arg("-o")
+        .arg(&object)
+        .status().expect("Nasm failed to run")
+        .success() {
+            panic!("Error: Failed to compile assembly");
+        }
+
+    let linked = match target {
+        Target::Windows => Command::new("golink")
+            .args(["/dll", "/entry", "_DllMain", object.as_str()])
+            .status().expect("Golink failed to run"),
+        Target::Linux => Command::new("ld")
+            .args(["-shared", "-o", &format!("temp/{name}.so"), object.as_str()])
+            .status().expect("ld failed to run"),
+    };
+
+    if !linked.success() {
+        panic!("Error: Failed to link assembly");
+    }
+
+    Ok(())
+}
+
+/// Deletes the files [`compile_asm`] created for `name`
+pub fn remove_build(name: &str, target: Target) {
+    let object = match target {
+        Target::Windows => "obj",
+        Target::Linux => "o",
+    };
+    for extension in ["asm", object, target.library_extension()] {
+        let _ = fs::remove_file(format!("temp/{name}.{extension}"));
+    }
+}
+
+pub type MemoryHandler = unsafe extern "C" fn(mem_space: *mut u8, addr: usize, io: *mut IoBus);
+
+pub type CompiledMain = unsafe extern "C" fn(mem_space: *mut u8, registers: *mut u8, on_mem_read: MemoryHandler, on_mem_write: MemoryHandler, instruction_count: *mut usize, io: *mut IoBus) -> u64;
\ No newline at end of file
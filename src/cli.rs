@@ -1,13 +1,31 @@
-use std::path::PathBuf;
+use std::{ops::RangeInclusive, path::{Path, PathBuf}};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use batpu_emulator::{
+    clock,
+    device::CharacterEncoding,
+    interpreter::{CallStackPolicy, ExecutionOptions},
+    rewind::RewindBuffer,
+    rom::ProgramFormat,
+    screenshot::{self, Colour, ScreenshotOptions},
+    trace::{self, TraceFilter, TraceFormat},
+    transpiler::Target,
+};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Input file
-    #[arg(short, long)]
-    pub input: PathBuf,
+    #[arg(short, long, required = true)]
+    pub input: Option<PathBuf>,
+
+    /// Format of the input file (detected from its extension if omitted)
+    #[arg(long, value_enum)]
+    pub format: Option<ProgramFormat>,
 
     /// Runs the program in benchmark mode
     #[arg(short, long)]
@@ -17,7 +35,207 @@ pub struct Args {
     #[arg(short, long)]
     pub no_gui: bool,
 
+    /// Runs the program in the interactive step debugger (interpreter backend only)
+    #[arg(short, long, conflicts_with_all = ["benchmark", "iterations", "clock_hz"])]
+    pub debug: bool,
+
+    /// What happens when a `cal` overflows or a `ret` underflows the 16 entry call stack
+    #[arg(long, value_enum, default_value_t = CallStackPolicy::Halt)]
+    pub call_stack_policy: CallStackPolicy,
+
+    /// Report executing past the last loaded instruction as an error instead of running into the empty ROM
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Config file mapping keyboard keys to controller buttons
+    #[arg(long)]
+    pub keymap: Option<PathBuf>,
+
+    /// Replays controller input from a script instead of reading the keyboard
+    #[arg(long, conflicts_with = "record_input")]
+    pub input_script: Option<PathBuf>,
+
+    /// Records the controller input seen by the program to a script that `--input-script` can replay
+    #[arg(long)]
+    pub record_input: Option<PathBuf>,
+
+    /// How bytes written to the character display are decoded
+    #[arg(long, value_enum, default_value_t = CharacterEncoding::Batpu)]
+    pub character_encoding: CharacterEncoding,
+
+    /// Saves the screen to a .png or .pbm file once the program stops
+    #[arg(long)]
+    pub screenshot_on_halt: Option<PathBuf>,
+
+    /// Size of each screen pixel in screenshots and recordings, from 1 to 128
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..=screenshot::MAX_SCALE as i64))]
+    pub screenshot_scale: u32,
+
+    /// Colour of lit pixels in PNG screenshots and GIF recordings, as rrggbb hex (PBM is always black and white)
+    #[arg(long, default_value = "ffffff", value_parser = screenshot::parse_colour)]
+    pub screenshot_on_colour: Colour,
+
+    /// Colour of unlit pixels in PNG screenshots and GIF recordings, as rrggbb hex (PBM is always black and white)
+    #[arg(long, default_value = "555555", value_parser = screenshot::parse_colour)]
+    pub screenshot_off_colour: Colour,
+
+    /// Records every screen push to an animated GIF, using the screenshot scale and colours
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+
+    /// Instructions per second of emulated time when playing back `--record` GIFs
+    #[arg(long, default_value_t = 1000)]
+    pub record_clock_hz: u64,
+
+    /// Starts the program from a save state made with F5 or the debugger (interpreter backend only)
+    #[arg(long)]
+    pub load_state: Option<PathBuf>,
+
+    /// Memory for the history used to rewind with Backspace or the debugger, in MiB (0 disables rewinding)
+    #[arg(long, default_value_t = 16)]
+    pub rewind_memory: usize,
+
+    /// Instructions between the checkpoints kept for rewinding
+    #[arg(long, default_value_t = 10_000)]
+    pub rewind_interval: usize,
+
+    /// Logs every executed instruction to a file (interpreter backend only)
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+
+    /// Format of `--trace` files
+    #[arg(long, value_enum, default_value_t = TraceFormat::Text)]
+    pub trace_format: TraceFormat,
+
+    /// Only traces instructions at these ROM addresses, as comma-separated ranges like `16-40,100`
+    #[arg(long, value_delimiter = ',', value_parser = trace::parse_range)]
+    pub trace_addresses: Vec<RangeInclusive<usize>>,
+
+    /// Only traces the instructions numbered in this range, counting from 1, like `5000-6000` or `5000-`
+    #[arg(long, value_parser = trace::parse_range)]
+    pub trace_window: Option<RangeInclusive<usize>>,
+
+    /// Writes a coverage report of the executed instructions and branch directions to a directory, along with an
+    /// annotated source listing and lcov file (interpreter backend only)
+    #[arg(long)]
+    pub coverage: Option<PathBuf>,
+
+    /// Writes a profile of the hottest addresses, routines and blocks to a directory, along with a folded stack file
+    /// for flamegraph tools (interpreter backend only)
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    /// Label names for the debugger, coverage and profile, as `<address> <name>` lines (replaces the assembler's labels)
+    #[arg(long)]
+    pub symbols: Option<PathBuf>,
+
+    /// Paces the program to this many instructions per second, or to one per tick with `redstone-tick` (10) or
+    /// `game-tick` (20). Tab toggles between this rate and full speed. The native and JIT backends are only paced when
+    /// the program accesses a device, so the interpreter is the default backend with this option.
+    #[arg(long, value_parser = clock::parse_clock_hz)]
+    pub clock_hz: Option<u64>,
+
+    /// Seed for the random number port at address 254 (a random seed is chosen and printed if omitted)
+    #[arg(long)]
+    pub seed: Option<u64>,
+
     /// Number of iterations to run the program
     #[arg(long, default_value_t = 1)]
-    pub iterations: usize
-}
\ No newline at end of file
+    pub iterations: usize,
+
+    /// Backend used to execute the program (defaults to native if nasm is available and neither `--clock-hz` nor an
+    /// interpreter-only option is used)
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+
+    /// Platform the native backend compiles for (defaults to the host platform)
+    #[arg(long, value_enum)]
+    pub target: Option<Target>
+}
+
+impl Args {
+    /// The program to run, which is required unless a subcommand is given
+    pub fn input(&self) -> &Path {
+        self.input.as_deref().expect("--input is required without a subcommand")
+    }
+
+    /// Whether the compiled backends need to maintain the instruction counter outside of benchmarks
+    pub fn needs_instruction_count(&self) -> bool {
+        self.input_script.is_some() || self.record_input.is_some() || self.record.is_some() || self.clock_hz.is_some()
+    }
+
+    /// The file the save state hotkeys write and read: the input file with a `.state` extension
+    pub fn state_file(&self) -> PathBuf {
+        self.input().with_extension("state")
+    }
+
+    /// The first option given that only the interpreter backend supports
+    pub fn interpreter_only_option(&self) -> Option<&'static str> {
+        if self.debug {
+            Some("--debug")
+        } else if self.load_state.is_some() {
+            Some("--load-state")
+        } else if self.trace.is_some() {
+            Some("--trace")
+        } else if self.coverage.is_some() {
+            Some("--coverage")
+        } else if self.profile.is_some() {
+            Some("--profile")
+        } else {
+            None
+        }
+    }
+
+    pub fn trace_filter(&self) -> TraceFilter {
+        TraceFilter {
+            addresses: self.trace_addresses.clone(),
+            window: self.trace_window.clone(),
+        }
+    }
+
+    pub fn rewind_buffer(&self) -> Option<RewindBuffer> {
+        (self.rewind_memory > 0).then(|| RewindBuffer::new(self.rewind_interval, self.rewind_memory << 20))
+    }
+
+    pub fn screenshot_options(&self) -> ScreenshotOptions {
+        ScreenshotOptions {
+            scale: self.screenshot_scale,
+            on: self.screenshot_on_colour,
+            off: self.screenshot_off_colour,
+        }
+    }
+
+    pub fn execution_options(&self) -> ExecutionOptions {
+        ExecutionOptions {
+            call_stack_policy: self.call_stack_policy,
+            strict: self.strict,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Prints machine code as assembly that assembles back to the same machine code
+    Disasm {
+        /// The machine code file to disassemble
+        input: PathBuf,
+
+        /// Format of the input file (detected from its extension if omitted)
+        #[arg(long, value_enum)]
+        format: Option<ProgramFormat>,
+
+        /// Writes the assembly to a file instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Transpiles the program to x86 assembly and runs it natively (requires nasm)
+    Native,
+    /// Compiles the program to x86 machine code in-process, without nasm or a linker
+    Jit,
+    /// Interprets the decoded instructions directly
+    Interpreter
+}
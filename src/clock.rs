@@ -0,0 +1,87 @@
+use std::{hint, thread, time::{Duration, Instant}};
+
+/// Minecraft's tick rates, as instructions per second for `--clock-hz`
+pub const PRESETS: [(&str, u64); 2] = [("redstone-tick", 10), ("game-tick", 20)];
+
+/// How far behind schedule a program may fall before the schedule restarts from where it is,
+/// so it doesn't race to catch up after a pause or an unthrottled stretch
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// The last stretch of every wait is spent spinning, since sleeps can overshoot by about this much
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+/// Paces a program to a fixed number of instructions per second.
+///
+/// Each instruction is due at a fixed offset from the start of the schedule, so sleeping too long for one doesn't
+/// delay the ones after it.
+pub struct Throttle {
+    clock_hz: u64,
+    /// When the instruction count was `start_count`
+    start: Instant,
+    start_count: usize,
+    /// Whether the last call to [`pace`](Self::pace) was unthrottled
+    unthrottled: bool,
+}
+
+impl Throttle {
+    pub fn new(clock_hz: u64) -> Self {
+        Self {
+            clock_hz: clock_hz.max(1),
+            start: Instant::now(),
+            start_count: 0,
+            unthrottled: false,
+        }
+    }
+
+    pub fn clock_hz(&self) -> u64 {
+        self.clock_hz
+    }
+
+    /// Instructions to run between calls to [`pace`](Self::pace), about a millisecond's worth, at most `max`
+    pub fn slice(&self, max: usize) -> usize {
+        (self.clock_hz as usize / 1000).clamp(1, max)
+    }
+
+    /// Waits until `instruction_count` instructions are due. Returns whether `unthrottled` changed since the last call.
+    pub fn pace(&mut self, instruction_count: usize, unthrottled: bool) -> bool {
+        let toggled = unthrottled != self.unthrottled;
+        self.unthrottled = unthrottled;
+
+        let now = Instant::now();
+        // The count goes backwards when the program is reset, rewound or a state is loaded
+        let due = instruction_count.checked_sub(self.start_count)
+            .map(|count| self.start + Duration::from_nanos((count as u128 * 1_000_000_000 / self.clock_hz as u128) as u64));
+        if unthrottled || due.is_none_or(|due| due + MAX_LAG < now) {
+            self.start = now;
+            self.start_count = instruction_count;
+            return toggled;
+        }
+
+        let due = due.unwrap();
+        if let Some(wait) = due.checked_duration_since(now) {
+            if wait > SPIN_TIME {
+                thread::sleep(wait - SPIN_TIME);
+            }
+            while Instant::now() < due {
+                hint::spin_loop();
+            }
+        }
+        toggled
+    }
+}
+
+/// Parses a clock rate, either a number of instructions per second or one of the [`PRESETS`]
+pub fn parse_clock_hz(text: &str) -> Result<u64, String> {
+    if let Some(&(_, hz)) = PRESETS.iter().find(|(name, _)| *name == text) {
+        return Ok(hz);
+    }
+
+    match text.parse() {
+        Ok(0) => Err("the clock rate must be at least 1".to_string()),
+        Ok(hz) => Ok(hz),
+        Err(_) => {
+            let presets = PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+            Err(format!("`{text}` is not a number or one of {presets}"))
+        },
+    }
+}
@@ -0,0 +1,219 @@
+use std::{fmt::Write as _, fs, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    interpreter::{CpuState, Interpreter, Observer},
+    report::{self, percent},
+    transpiler::{Condition, Instruction},
+};
+
+/// The program's source file and the line of each instruction in it
+pub struct SourceMap {
+    pub path: PathBuf,
+    pub src: String,
+    /// The (1-based) source line of each instruction
+    pub lines: Vec<usize>,
+}
+
+/// Counts how often every instruction of the program runs and which ways its branches go.
+///
+/// Once the run is over it writes to its output directory:
+/// - `coverage.txt`, the execution count of every address and the taken/not taken counts of every branch
+/// - `source.txt`, the source annotated with execution counts, if the source is known
+/// - `lcov.info`, for coverage tools, if the source is known
+///
+/// and prints a summary per labelled block.
+pub struct Coverage {
+    output: PathBuf,
+    instructions: Vec<Instruction>,
+    /// Label names and addresses, sorted by address
+    labels: Vec<(String, u16)>,
+    source: Option<SourceMap>,
+    counts: Vec<u64>,
+    /// Times each branch was not taken and taken
+    branches: Vec<[u64; 2]>,
+}
+
+impl Coverage {
+    /// Generates labels from the jump and call targets if `labels` is empty
+    pub fn new(output: &Path, instructions: &[Instruction], labels: &[(String, u16)], source: Option<SourceMap>) -> Self {
+        Self {
+            output: output.to_path_buf(),
+            instructions: instructions.to_vec(),
+            labels: report::sorted_labels(instructions, labels),
+            source,
+            counts: vec![0; instructions.len()],
+            branches: vec![[0; 2]; instructions.len()],
+        }
+    }
+
+    fn is_branch(&self, addr: usize) -> bool {
+        matches!(self.instructions[addr], Instruction::Brh(..))
+    }
+
+    /// Number of branch directions at `addr` that were exercised
+    fn directions_hit(&self, addr: usize) -> usize {
+        self.branches[addr].iter().filter(|&&count| count > 0).count()
+    }
+
+    /// The labelled blocks as name and address range, with any code before the first label in a `(start)` block
+    fn blocks(&self) -> Vec<(&str, usize, usize)> {
+        let mut starts = self.labels.iter()
+            .map(|(name, addr)| (name.as_str(), *addr as usize))
+            .filter(|&(_, addr)| addr < self.instructions.len())
+            .collect::<Vec<_>>();
+        if starts.first().is_none_or(|&(_, addr)| addr > 0) {
+            starts.insert(0, ("(start)", 0));
+        }
+
+        starts.iter().enumerate()
+            .map(|(i, &(name, start))| (name, start, starts.get(i + 1).map_or(self.instructions.len(), |&(_, end)| end)))
+            .filter(|&(_, start, end)| start < end)
+            .collect()
+    }
+
+    fn address_report(&self) -> String {
+        let mut report = String::from("addr      count  instruction                   branch taken/not taken\n");
+        let mut labels = self.labels.iter().peekable();
+
+        for (addr, instruction) in self.instructions.iter().enumerate() {
+            while let Some((name, _)) = labels.next_if(|&(_, label)| *label as usize <= addr) {
+                writeln!(report, "{name}:").unwrap();
+            }
+
+            let count = match self.counts[addr] {
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            let mut line = format!("{addr:04} {count:>10}  {:<28}", format!("{instruction:?}"));
+            if self.is_branch(addr) {
+                let [not_taken, taken] = self.branches[addr];
+                write!(line, "  {taken}/{not_taken}").unwrap();
+            }
+            writeln!(report, "{}", line.trim_end()).unwrap();
+        }
+
+        report
+    }
+
+    /// Execution count of each source line that has instructions on it
+    fn line_counts(source: &SourceMap, counts: &[u64]) -> Vec<Option<u64>> {
+        let mut line_counts = vec![None; source.src.lines().count() + 1];
+        for (&line, &count) in source.lines.iter().zip(counts) {
+            if let Some(line_count) = line_counts.get_mut(line) {
+                *line_count = Some(line_count.unwrap_or(0) + count);
+            }
+        }
+        line_counts
+    }
+
+    fn annotated_source(&self, source: &SourceMap) -> String {
+        let line_counts = Self::line_counts(source, &self.counts);
+
+        source.src.lines().enumerate()
+            .map(|(i, text)| {
+                let count = match line_counts[i + 1] {
+                    None => "-".to_string(),
+                    Some(0) => "#####".to_string(),
+                    Some(count) => count.to_string(),
+                };
+                format!("{count:>10}: {:>5}: {text}\n", i + 1)
+            })
+            .collect()
+    }
+
+    fn lcov(&self, source: &SourceMap) -> String {
+        let path = fs::canonicalize(&source.path).unwrap_or_else(|_| source.path.clone());
+        let mut lcov = format!("TN:\nSF:{}\n", path.display());
+
+        let blocks = self.blocks();
+        for &(name, start, _) in &blocks {
+            writeln!(lcov, "FN:{},{name}", source.lines[start]).unwrap();
+        }
+        for &(name, start, _) in &blocks {
+            writeln!(lcov, "FNDA:{},{name}", self.counts[start]).unwrap();
+        }
+        let blocks_hit = blocks.iter().filter(|&&(_, start, _)| self.counts[start] > 0).count();
+        writeln!(lcov, "FNF:{}\nFNH:{blocks_hit}", blocks.len()).unwrap();
+
+        let mut branches = 0;
+        let mut branches_hit = 0;
+        for addr in (0..self.instructions.len()).filter(|&addr| self.is_branch(addr)) {
+            let [not_taken, taken] = self.branches[addr];
+            for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                let count = if self.counts[addr] == 0 { "-".to_string() } else { count.to_string() };
+                writeln!(lcov, "BRDA:{},{addr},{branch},{count}", source.lines[addr]).unwrap();
+            }
+            branches += 2;
+            branches_hit += self.directions_hit(addr);
+        }
+        writeln!(lcov, "BRF:{branches}\nBRH:{branches_hit}").unwrap();
+
+        let line_counts = Self::line_counts(source, &self.counts);
+        let lines = line_counts.iter().enumerate()
+            .filter_map(|(line, count)| Some((line, (*count)?)))
+            .collect::<Vec<_>>();
+        for &(line, count) in &lines {
+            writeln!(lcov, "DA:{line},{count}").unwrap();
+        }
+        let lines_hit = lines.iter().filter(|&&(_, count)| count > 0).count();
+        writeln!(lcov, "LF:{}\nLH:{lines_hit}\nend_of_record", lines.len()).unwrap();
+
+        lcov
+    }
+
+    fn print_summary(&self) {
+        let summarise = |start: usize, end: usize| {
+            let executed = (start..end).filter(|&addr| self.counts[addr] > 0).count();
+            let branches = (start..end).filter(|&addr| self.is_branch(addr)).count() * 2;
+            let branches_hit = (start..end).filter(|&addr| self.is_branch(addr)).map(|addr| self.directions_hit(addr)).sum::<usize>();
+            format!("{executed}/{} instructions ({}), {branches_hit}/{branches} branch directions", end - start, percent(executed as u64, (end - start) as u64))
+        };
+
+        println!("Coverage: {}", summarise(0, self.instructions.len()));
+        let width = self.blocks().iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+        for (name, start, end) in self.blocks() {
+            println!("  {name:<width$}  {}", summarise(start, end));
+        }
+    }
+}
+
+impl Observer for Coverage {
+    fn observe(&mut self, before: &CpuState, _interpreter: &Interpreter) {
+        let addr = before.pc as usize;
+        // The nop padding after the program isn't part of it
+        if addr >= self.counts.len() {
+            return;
+        }
+
+        self.counts[addr] += 1;
+        if let Instruction::Brh(condition, _) = before.instruction {
+            let taken = match condition {
+                Condition::Equal => before.zero,
+                Condition::NotEqual => !before.zero,
+                Condition::GreaterThanOrEqual => before.carry,
+                Condition::LessThan => !before.carry,
+            };
+            self.branches[addr][taken as usize] += 1;
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.output).with_context(|| format!("Failed to create {}", self.output.display()))?;
+
+        let mut files = vec![("coverage.txt", self.address_report())];
+        if let Some(source) = &self.source {
+            files.push(("source.txt", self.annotated_source(source)));
+            files.push(("lcov.info", self.lcov(source)));
+        }
+
+        for (name, contents) in files {
+            let path = self.output.join(name);
+            fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        self.print_summary();
+        Ok(())
+    }
+}
@@ -0,0 +1,375 @@
+use std::{collections::{BTreeSet, HashMap}, io::{self, BufRead, Write}, path::Path};
+
+use batpu_emulator::{
+    assembler,
+    interface,
+    interpreter::{Interpreter, ROM_SIZE},
+    rewind::RewindBuffer,
+    save_state,
+    transpiler::{self, Instruction},
+};
+
+const HELP: &str = "\
+Commands:
+  s, step [n]            Execute the next n instructions (default 1)
+  c, continue            Run until a breakpoint is hit or the program halts
+  rs, reverse-step [n]   Undo the last n instructions (default 1)
+  rc, reverse-continue   Run backwards to the previous breakpoint hit
+  b, break <addr|label>  Set a breakpoint
+  d, delete <addr|label> Clear a breakpoint
+  breakpoints            List breakpoints
+  r, regs                Print the registers
+  f, flags               Print the zero and carry flags
+  bt, stack              Print the call stack
+  m, mem <start> [end]   Print memory from start to end (inclusive)
+  l, list [addr] [n]     Disassemble n instructions around addr (defaults to the PC)
+  screen                 Print the contents of the screen and character display
+  save <file>            Save the machine state to a file
+  load <file>            Restore the machine state from a file
+  reset                  Restart the program
+  q, quit                Exit the debugger
+An empty line repeats the previous command.";
+
+/// Interactive debugger that drives the interpreter one instruction at a time
+pub struct Debugger {
+    pub interpreter: Interpreter,
+    /// Label names and addresses, either from the assembler or generated from jump targets
+    labels: Vec<(String, u16)>,
+    breakpoints: BTreeSet<u16>,
+    /// History for reverse execution, if enabled
+    rewind: Option<RewindBuffer>,
+}
+
+impl Debugger {
+    pub fn new(interpreter: Interpreter, labels: Vec<(String, u16)>, rewind: Option<RewindBuffer>) -> Self {
+        let labels = if labels.is_empty() {
+            transpiler::generated_labels(interpreter.instructions())
+        } else {
+            labels
+        };
+
+        Self {
+            interpreter,
+            labels,
+            breakpoints: BTreeSet::new(),
+            rewind,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("BatPU-2 debugger. Type `help` for a list of commands.");
+        self.print_location();
+
+        let mut stdin = io::stdin().lock();
+        let mut previous = String::new();
+
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+
+            let line = match line.trim() {
+                "" => previous.clone(),
+                line => line.to_string(),
+            };
+
+            if !self.execute(&line) {
+                break;
+            }
+
+            previous = line;
+        }
+    }
+
+    /// Carries out one command, returning `false` if it was `quit`
+    fn execute(&mut self, line: &str) -> bool {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some(&command) = words.first() else {
+            return true;
+        };
+
+        match (command, &words[1..]) {
+            ("s" | "step", args) => match args.first().map(|n| self.parse_number(n)).unwrap_or(Some(1)) {
+                Some(n) => self.step(n as usize),
+                None => println!("Invalid step count"),
+            },
+            ("c" | "continue", []) => self.continue_execution(),
+            ("rs" | "reverse-step", args) => match args.first().map(|n| self.parse_number(n)).unwrap_or(Some(1)) {
+                Some(n) => self.reverse_step(n as usize),
+                None => println!("Invalid step count"),
+            },
+            ("rc" | "reverse-continue", []) => self.reverse_continue(),
+            ("b" | "break", [location]) => match self.parse_location(location) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    println!("Breakpoint set at {}", self.format_address(addr));
+                },
+                None => println!("Unknown address or label `{location}`"),
+            },
+            ("d" | "delete", [location]) => match self.parse_location(location) {
+                Some(addr) if self.breakpoints.remove(&addr) => println!("Breakpoint cleared at {}", self.format_address(addr)),
+                Some(addr) => println!("No breakpoint at {}", self.format_address(addr)),
+                None => println!("Unknown address or label `{location}`"),
+            },
+            ("breakpoints", []) => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for &addr in self.breakpoints.iter() {
+                    println!("  {}", self.format_address(addr));
+                }
+            },
+            ("r" | "regs", []) => self.print_registers(),
+            ("f" | "flags", []) => println!("Z={} C={}", self.interpreter.zero as u8, self.interpreter.carry as u8),
+            ("bt" | "stack", []) => self.print_call_stack(),
+            ("m" | "mem", [start]) => self.print_memory(start, start),
+            ("m" | "mem", [start, end]) => self.print_memory(start, end),
+            ("l" | "list", args) => {
+                let addr = args.first().map(|a| self.parse_location(a)).unwrap_or(Some(self.interpreter.pc));
+                let count = args.get(1).map(|n| self.parse_number(n).and_then(|n| u16::try_from(n).ok())).unwrap_or(Some(10));
+                match (addr, count) {
+                    (Some(addr), Some(count)) => self.print_listing(addr, count),
+                    (None, _) => println!("Unknown address or label `{}`", args[0]),
+                    (_, None) => println!("Invalid count"),
+                }
+            },
+            ("screen", []) => self.print_screen(),
+            ("save", [path]) => match save_state::save_file(&self.interpreter, Path::new(path)) {
+                Ok(()) => println!("Saved state to {path}"),
+                Err(err) => println!("Error: {err:#}"),
+            },
+            ("load", [path]) => match save_state::load_file(&mut self.interpreter, Path::new(path)) {
+                Ok(()) => {
+                    self.clear_rewind();
+                    self.print_location();
+                },
+                Err(err) => println!("Error: {err:#}"),
+            },
+            ("reset", []) => {
+                self.interpreter.reset();
+                self.clear_rewind();
+                self.print_location();
+            },
+            ("q" | "quit", []) => return false,
+            ("h" | "help", []) => println!("{HELP}"),
+            _ => println!("Unknown command `{line}`. Type `help` for a list of commands."),
+        }
+
+        true
+    }
+
+    fn step(&mut self, count: usize) {
+        for _ in 0..count {
+            if !self.step_instruction() {
+                break;
+            }
+        }
+        self.print_location();
+    }
+
+    pub fn continue_execution(&mut self) {
+        // Always execute at least one instruction so continuing from a breakpoint makes progress
+        while self.step_instruction() {
+            if self.breakpoints.contains(&self.interpreter.pc) {
+                println!("Hit breakpoint at {}", self.format_address(self.interpreter.pc));
+                break;
+            }
+        }
+        self.print_location();
+    }
+
+    /// Executes one instruction, checkpointing the machine first if rewinding is enabled
+    fn step_instruction(&mut self) -> bool {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.record(&mut self.interpreter);
+        }
+        self.interpreter.step()
+    }
+
+    fn reverse_step(&mut self, count: usize) {
+        let Some(rewind) = &mut self.rewind else {
+            println!("Rewinding is disabled");
+            return;
+        };
+
+        let target = self.interpreter.instruction_count.saturating_sub(count);
+        if rewind.rewind_to(&mut self.interpreter, target) > target {
+            println!("Reached the start of the rewind history");
+        }
+        self.print_location();
+    }
+
+    fn reverse_continue(&mut self) {
+        let Some(rewind) = &mut self.rewind else {
+            println!("Rewinding is disabled");
+            return;
+        };
+
+        let breakpoints = &self.breakpoints;
+        if rewind.rewind_until(&mut self.interpreter, |interpreter| breakpoints.contains(&interpreter.pc)) {
+            println!("Hit breakpoint at {}", self.format_address(self.interpreter.pc));
+        } else {
+            println!("Reached the start of the rewind history");
+        }
+        self.print_location();
+    }
+
+    fn clear_rewind(&mut self) {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.interpreter.halted
+    }
+
+    fn print_location(&self) {
+        if let Some(fault) = self.interpreter.fault {
+            println!("{fault}");
+        }
+
+        if self.interpreter.halted {
+            println!("Program halted after {} instructions", self.interpreter.instruction_count);
+        } else {
+            self.print_listing(self.interpreter.pc, 1);
+        }
+    }
+
+    fn print_listing(&self, addr: u16, count: u16) {
+        let start = if addr == self.interpreter.pc { addr } else { addr.saturating_sub(count / 2) };
+        let label_map = self.labels.iter().map(|(name, addr)| (*addr, name.clone())).collect::<HashMap<_, _>>();
+
+        for addr in start..start.saturating_add(count).min(ROM_SIZE as u16) {
+            let instruction = self.interpreter.instructions().get(addr as usize).unwrap_or(&Instruction::Nop);
+
+            for (name, _) in self.labels.iter().filter(|(_, a)| *a == addr) {
+                println!("{name}:");
+            }
+
+            let marker = if addr == self.interpreter.pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&addr) { "*" } else { " " };
+            println!("{marker}{breakpoint} {addr:04}  {}", instruction.to_assembly(&label_map));
+        }
+    }
+
+    fn print_registers(&self) {
+        for (i, value) in self.interpreter.registers.iter().enumerate() {
+            print!("r{i:<2} = {value:3} (0x{value:02x})");
+            if i % 4 == 3 {
+                println!();
+            } else {
+                print!("   ");
+            }
+        }
+    }
+
+    fn print_call_stack(&self) {
+        if self.interpreter.call_stack.is_empty() {
+            println!("Call stack is empty");
+        }
+        for (depth, addr) in self.interpreter.call_stack.iter().enumerate() {
+            println!("#{depth} returns to {}", self.format_address(addr));
+        }
+    }
+
+    fn print_memory(&self, start: &str, end: &str) {
+        let (Some(start), Some(end)) = (self.parse_number(start), self.parse_number(end)) else {
+            println!("Invalid memory range");
+            return;
+        };
+        if start > end || end > 255 {
+            println!("Memory range must be within [0, 255]");
+            return;
+        }
+
+        for row in (start / 16)..=(end / 16) {
+            print!("{:3}:", row * 16);
+            for addr in (row * 16)..(row * 16 + 16) {
+                if (start..=end).contains(&addr) {
+                    print!(" {:02x}", self.interpreter.memory[addr as usize]);
+                } else {
+                    print!("   ");
+                }
+            }
+            println!();
+        }
+    }
+
+    fn print_screen(&self) {
+        let panel = self.interpreter.io.state.panel.lock().unwrap();
+        for row in panel.screen.iter().rev() {
+            println!("{}", row.iter().map(|&pixel| if pixel { "██" } else { "  " }).collect::<String>());
+        }
+        println!("[{:<width$}]", panel.character_display.iter().collect::<String>(), width = interface::CHARACTER_DISPLAY_WIDTH);
+    }
+
+    fn format_address(&self, addr: u16) -> String {
+        match self.labels.iter().find(|(_, a)| *a == addr) {
+            Some((name, _)) => format!("{addr} ({name})"),
+            None => addr.to_string(),
+        }
+    }
+
+    /// An address in the ROM, or the address of a label
+    fn parse_location(&self, location: &str) -> Option<u16> {
+        if let Some(addr) = self.parse_number(location) {
+            return u16::try_from(addr).ok().filter(|&addr| (addr as usize) < ROM_SIZE);
+        }
+
+        let label = assembler::label_name(location);
+        self.labels.iter()
+            .find(|(name, _)| *name == label)
+            .map(|&(_, addr)| addr)
+    }
+
+    fn parse_number(&self, text: &str) -> Option<u32> {
+        assembler::parse_number(text).and_then(|n| u32::try_from(n).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use batpu_emulator::interface::IoBus;
+
+    use super::*;
+
+    fn debugger(src: &str, symbols: &str) -> Debugger {
+        let program = assembler::assemble(src, Path::new("test.as")).unwrap().program;
+        let interpreter = Interpreter::new(transpiler::disassemble(&program), Default::default(), IoBus::new(0));
+        Debugger::new(interpreter, assembler::parse_symbols(symbols).unwrap(), None)
+    }
+
+    #[test]
+    fn breakpoints_on_symbol_file_labels() {
+        let mut debugger = debugger("ldi r1 3\nadi r1 -1\nbrh ne 1\nhlt", "1 Loop\n3 main");
+
+        assert!(debugger.execute("b Loop"));
+        assert!(debugger.execute("b .MAIN"));
+        assert_eq!(debugger.breakpoints, BTreeSet::from([1, 3]));
+
+        debugger.execute("c");
+        assert_eq!(debugger.interpreter.pc, 1);
+
+        debugger.execute("d loop");
+        debugger.execute("c");
+        assert_eq!(debugger.interpreter.pc, 3);
+        assert_eq!(debugger.interpreter.registers[1], 0);
+    }
+
+    #[test]
+    fn locations_must_be_in_the_rom() {
+        let debugger = debugger("hlt", "");
+
+        assert_eq!(debugger.parse_location("1023"), Some(1023));
+        assert_eq!(debugger.parse_location("1024"), None);
+        assert_eq!(debugger.parse_location("5000"), None);
+        assert_eq!(debugger.parse_location("65537"), None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use arrayvec::ArrayVec;
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use crate::{
+    assembler,
+    clock::Throttle,
+    gif_recorder::GifRecorder,
+    input_script::{InputRecorder, InputScript},
+    interface::{self, CharacterLine, NumberDisplaySettings, Panel, PixelBuffer},
+    save_state::{StateReader, StateWriter},
+};
+
+/// A memory-mapped peripheral, attached to a range of addresses with [`IoBus::attach`](crate::interface::IoBus::attach)
+pub trait Device: Send {
+    /// Called when the program reads `addr`. Returning a value replaces what is in memory at that address,
+    /// `None` leaves the last value written there.
+    fn read(&mut self, addr: u8, state: &mut BusState) -> Option<u8>;
+
+    /// Called after the program writes `value` to `addr`
+    fn write(&mut self, addr: u8, value: u8, state: &mut BusState);
+
+    /// Called when the machine is reset, before every iteration of a run
+    fn reset(&mut self) {}
+
+    /// Called before every memory access, with the instruction count in `state` saying how far the program has got
+    fn tick(&mut self, _state: &mut BusState) {}
+
+    /// Writes any internal state to a save state
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    /// Restores the internal state written by [`save_state`](Self::save_state)
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Machine state shared by every device on a bus
+pub struct BusState {
+    pub panel: Arc<Mutex<Panel>>,
+    /// Instructions executed so far, used to timestamp controller input and screen pushes.
+    /// Compiled programs count into it directly.
+    pub instruction_count: usize,
+    /// Number of times the screen buffer has been pushed
+    pub frame_count: usize,
+    /// Replaces keyboard input when set
+    pub input_script: Option<InputScript>,
+    pub input_recorder: Option<InputRecorder>,
+    /// Every change in the controller byte the program has read, with the instruction count it was read at.
+    /// Kept while rewinding is enabled so the input can be replayed.
+    pub input_log: Option<Vec<(usize, u8)>>,
+    pub gif_recorder: Option<GifRecorder>,
+    /// Paces the program to its `--clock-hz` rate whenever it accesses a device
+    pub throttle: Option<Throttle>,
+}
+
+impl BusState {
+    /// Waits until the instructions executed so far are due, unless the UI has turned throttling off
+    pub fn pace(&mut self) {
+        let Some(throttle) = &mut self.throttle else {
+            return;
+        };
+
+        // The UI needs the panel while the program waits
+        let unthrottled = self.panel.lock().unwrap().unthrottled;
+        if throttle.pace(self.instruction_count, unthrottled) {
+            self.panel.lock().unwrap().status = Some(if unthrottled {
+                "Running unthrottled".to_string()
+            } else {
+                format!("Running at {} instructions per second", throttle.clock_hz())
+            });
+        }
+    }
+}
+
+/// The 32x32 screen at 240-246
+#[derive(Default)]
+pub struct ScreenDevice {
+    x: usize,
+    y: usize,
+    pixel_buffer: PixelBuffer,
+}
+
+impl Device for ScreenDevice {
+    fn read(&mut self, addr: u8, _state: &mut BusState) -> Option<u8> {
+        match addr {
+            244 => Some(self.pixel_buffer[self.y][self.x] as u8), // Load Pixel at (Pixel X, Pixel Y)
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, addr: u8, value: u8, state: &mut BusState) {
+        match addr {
+            240 => self.x = (value & 0b11111) as usize, // Pixel X
+            241 => self.y = (value & 0b11111) as usize, // Pixel Y
+            242 => self.pixel_buffer[self.y][self.x] = true, // Draw pixel at (Pixel X, Pixel Y) to buffer
+            243 => self.pixel_buffer[self.y][self.x] = false, // Clear pixel at (Pixel X, Pixel Y) to buffer
+            245 => { // Push screen buffer
+                let mut panel = state.panel.lock().unwrap();
+                panel.screen = self.pixel_buffer;
+                panel.screen_dirty = true;
+                state.frame_count += 1;
+
+                if let Some(recorder) = &mut state.gif_recorder {
+                    recorder.push(state.instruction_count, &self.pixel_buffer);
+                }
+            }
+            246 => self.pixel_buffer = [[false; 32]; 32], // Clear screen buffer
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.x as u8);
+        state.u8(self.y as u8);
+        interface::save_pixel_buffer(&self.pixel_buffer, state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.x = (state.u8()? & 0b11111) as usize;
+        self.y = (state.u8()? & 0b11111) as usize;
+        self.pixel_buffer = interface::load_pixel_buffer(state)?;
+        Ok(())
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CharacterEncoding {
+    /// The BatPU-2's own 5 bit character set (space, a-z, `.`, `!` and `?`)
+    Batpu,
+    /// Plain ASCII, for programs written for older versions of this emulator
+    Ascii
+}
+
+/// The character display at 247-249
+pub struct TextDevice {
+    encoding: CharacterEncoding,
+    character_buffer: CharacterLine,
+}
+
+impl TextDevice {
+    pub fn new(encoding: CharacterEncoding) -> Self {
+        Self {
+            encoding,
+            character_buffer: ArrayVec::new(),
+        }
+    }
+
+    fn decode_character(&self, byte: u8) -> char {
+        match self.encoding {
+            // Only the low 5 bits are wired to the display, and the two unused codes are blank
+            CharacterEncoding::Batpu => assembler::CHARACTERS.get((byte & 0b11111) as usize).copied().unwrap_or(' '),
+            CharacterEncoding::Ascii => match byte as char {
+                c if c.is_ascii_graphic() => c,
+                _ => ' ',
+            },
+        }
+    }
+}
+
+impl Device for TextDevice {
+    fn read(&mut self, _addr: u8, _state: &mut BusState) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, addr: u8, value: u8, state: &mut BusState) {
+        match addr {
+            247 => { // Write character to buffer
+                // Like the hardware, characters written past the end of the display are dropped
+                let _ = self.character_buffer.try_push(self.decode_character(value));
+            }
+            248 => { // Push character buffer
+                let mut panel = state.panel.lock().unwrap();
+                panel.character_display = self.character_buffer.clone();
+                panel.character_display_dirty = true;
+            }
+            249 => self.character_buffer.clear(), // Clear character buffer
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.character_buffer.clear();
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        interface::save_character_line(&self.character_buffer, state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.character_buffer = interface::load_character_line(state)?;
+        Ok(())
+    }
+}
+
+/// The number display at 250-253
+pub struct NumberDevice;
+
+impl Device for NumberDevice {
+    fn read(&mut self, _addr: u8, _state: &mut BusState) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, addr: u8, value: u8, state: &mut BusState) {
+        let mut panel = state.panel.lock().unwrap();
+        match addr {
+            250 => { // Show number display
+                panel.number_display = value;
+                panel.show_number_display = true;
+            }
+            251 => panel.show_number_display = false, // Clear number display
+            252 => panel.number_display_settings = NumberDisplaySettings::TwosCompliment, // Interpret number as 2s comp [-128, 127]
+            253 => panel.number_display_settings = NumberDisplaySettings::Unsigned, // Interpret number as unsigned int [0, 255]
+            _ => return,
+        }
+        panel.number_display_dirty = true;
+    }
+}
+
+/// The random number generator at 254, restarted from its seed on every reset.
+/// This is the generator behind `StdRng`, used directly so save states can store its position in the stream.
+pub struct RngDevice {
+    seed: u64,
+    rng: ChaCha12Rng,
+}
+
+impl RngDevice {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Device for RngDevice {
+    fn read(&mut self, _addr: u8, _state: &mut BusState) -> Option<u8> {
+        Some(self.rng.gen::<u8>()) // Load a random 8 bit number
+    }
+
+    fn write(&mut self, _addr: u8, _value: u8, _state: &mut BusState) {}
+
+    fn reset(&mut self) {
+        *self = Self::new(self.seed);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.seed);
+        // Every number takes one 32 bit word, so the position can't outgrow 64 bits
+        state.u64(self.rng.get_word_pos() as u64);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        *self = Self::new(state.u64()?);
+        self.rng.set_word_pos(state.u64()?.into());
+        Ok(())
+    }
+}
+
+/// The controller at 255, driven by the panel or an input script
+pub struct ControllerDevice;
+
+impl Device for ControllerDevice {
+    fn read(&mut self, _addr: u8, state: &mut BusState) -> Option<u8> {
+        let mut panel = state.panel.lock().unwrap();
+
+        if let Some(script) = &mut state.input_script {
+            panel.controller.set_value(script.value_at(state.instruction_count, state.frame_count));
+        }
+        if let Some(recorder) = &mut state.input_recorder {
+            recorder.record(state.instruction_count, panel.controller.value());
+        }
+        if let Some(log) = &mut state.input_log {
+            if log.last().map(|&(_, value)| value) != Some(panel.controller.value()) {
+                log.push((state.instruction_count, panel.controller.value()));
+            }
+        }
+
+        Some(panel.controller.value())
+    }
+
+    fn write(&mut self, _addr: u8, _value: u8, _state: &mut BusState) {}
+}
@@ -0,0 +1,42 @@
+use std::{collections::HashMap, fmt::Write as _};
+
+use crate::transpiler::{self, EncodedInstruction};
+
+/// Column the address and encoding comments start at
+const COMMENT_COLUMN: usize = 28;
+
+/// Turns machine code back into source for the assembler.
+///
+/// Every jump, branch and call target in the program gets a `.label_<n>` label, and each instruction is followed by a
+/// comment with its address and encoding. Words with unused bits set can't be assembled back exactly; they are
+/// listed in a warning at the top.
+pub fn listing(program: &[u16]) -> String {
+    let instructions = transpiler::disassemble(program);
+    // Labels can only be placed up to the end of the program, targets past it stay numeric
+    let labels = transpiler::generated_labels(&instructions).into_iter()
+        .filter(|&(_, addr)| addr as usize <= program.len())
+        .collect::<Vec<_>>();
+    let label_map = labels.iter().map(|(name, addr)| (*addr, name.clone())).collect::<HashMap<_, _>>();
+
+    let mut body = String::new();
+    for (addr, (&word, instruction)) in program.iter().zip(&instructions).enumerate() {
+        if let Some(label) = label_map.get(&(addr as u16)) {
+            writeln!(body, "{label}").unwrap();
+        }
+        writeln!(body, "    {:<width$}// {addr:04} {word:016b}", instruction.to_assembly(&label_map), width = COMMENT_COLUMN - 4).unwrap();
+    }
+    if let Some(label) = label_map.get(&(program.len() as u16)) {
+        writeln!(body, "{label}").unwrap();
+    }
+
+    let inexact = program.iter().enumerate()
+        .filter(|&(_, &word)| EncodedInstruction(word).ignored_bits() != 0)
+        .map(|(addr, _)| addr.to_string())
+        .collect::<Vec<_>>();
+
+    if inexact.is_empty() {
+        body
+    } else {
+        format!("// Warning: unused bits are set in the words at {}, which assemble differently\n{body}", inexact.join(", "))
+    }
+}
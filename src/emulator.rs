@@ -0,0 +1,222 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::{
+    interface::{Button, IoBus, NumberDisplaySettings, PixelBuffer},
+    interpreter::{CallStack, ExecutionOptions, Fault, Interpreter, Observer, ROM_SIZE},
+    rewind::RewindBuffer,
+    rom, save_state, transpiler,
+};
+
+/// A BatPU-2 for embedding in other programs, driven one instruction at a time by the interpreter.
+///
+/// Every emulator has its own I/O devices, so any number of them can run at once.
+pub struct Emulator {
+    interpreter: Interpreter,
+    rewind: Option<RewindBuffer>,
+}
+
+impl Emulator {
+    pub fn new(rom: &[u16]) -> Result<Self> {
+        Self::with_options(rom, ExecutionOptions::default())
+    }
+
+    pub fn with_options(rom: &[u16], options: ExecutionOptions) -> Result<Self> {
+        Self::with_io(rom, options, IoBus::new(0))
+    }
+
+    /// Creates an emulator with preconfigured devices, such as a different random seed or an input script
+    pub fn with_io(rom: &[u16], options: ExecutionOptions, io: IoBus) -> Result<Self> {
+        if rom.len() > ROM_SIZE {
+            bail!("Program is {} words long, but the ROM only holds {ROM_SIZE}", rom.len());
+        }
+
+        Ok(Self {
+            interpreter: Interpreter::new(transpiler::disassemble(rom), options, io),
+            rewind: None,
+        })
+    }
+
+    /// Loads a program from the text of a `.mc` file, one binary instruction per line
+    pub fn from_mc(src: &str) -> Result<Self> {
+        Self::new(&rom::parse_mc(src)?.program)
+    }
+
+    /// Executes a single instruction, returning false once the program has stopped
+    pub fn step(&mut self) -> bool {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.record(&mut self.interpreter);
+        }
+        self.interpreter.step()
+    }
+
+    /// Executes up to `count` instructions, returning how many were executed before the program stopped
+    pub fn run_for(&mut self, count: usize) -> usize {
+        let start = self.interpreter.instruction_count;
+        for _ in 0..count {
+            if !self.step() {
+                break;
+            }
+        }
+        self.interpreter.instruction_count - start
+    }
+
+    /// Runs until the program halts or faults
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Restarts the program with cleared memory and registers
+    pub fn reset(&mut self) {
+        self.interpreter.reset();
+        self.clear_rewind();
+    }
+
+    fn clear_rewind(&mut self) {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+    }
+
+    /// Notifies `observer` of every instruction executed from now on
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.interpreter.observers.push(observer);
+    }
+
+    /// Finishes and removes every observer, for example writing out reports, returning the first error
+    pub fn finish_observers(&mut self) -> Result<()> {
+        self.interpreter.finish_observers()
+    }
+
+    /// Starts keeping a history in `buffer` to rewind through
+    pub fn enable_rewind(&mut self, buffer: RewindBuffer) {
+        self.rewind = Some(buffer);
+    }
+
+    pub fn rewind_enabled(&self) -> bool {
+        self.rewind.is_some()
+    }
+
+    /// Steps back `count` instructions, or as far as the history goes, returning how many were undone
+    pub fn rewind(&mut self, count: usize) -> usize {
+        let start = self.interpreter.instruction_count;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.rewind_to(&mut self.interpreter, start.saturating_sub(count));
+        }
+        start - self.interpreter.instruction_count
+    }
+
+    /// Steps back to just before the screen was last pushed, or `max_count` instructions if that is nearer.
+    /// Returns false once there is no more history.
+    pub fn rewind_frame(&mut self, max_count: usize) -> bool {
+        let frame = self.interpreter.io.state.frame_count;
+        let start = self.interpreter.instruction_count;
+        match &mut self.rewind {
+            Some(rewind) => rewind.rewind_until(&mut self.interpreter, |interpreter| {
+                interpreter.io.state.frame_count < frame || interpreter.instruction_count + max_count <= start
+            }),
+            None => false,
+        }
+    }
+
+    /// The complete machine state in the save state format, which [`restore`](Self::restore) returns to
+    pub fn snapshot(&self) -> Vec<u8> {
+        save_state::save(&self.interpreter)
+    }
+
+    /// Returns to a state from [`snapshot`](Self::snapshot) or a save state file taken with the same program
+    pub fn restore(&mut self, state: &[u8]) -> Result<()> {
+        save_state::load(&mut self.interpreter, state)?;
+        self.clear_rewind();
+        Ok(())
+    }
+
+    pub fn save_state(&self, path: &Path) -> Result<()> {
+        save_state::save_file(&self.interpreter, path)
+    }
+
+    pub fn load_state(&mut self, path: &Path) -> Result<()> {
+        save_state::load_file(&mut self.interpreter, path)?;
+        self.clear_rewind();
+        Ok(())
+    }
+
+    pub fn halted(&self) -> bool {
+        self.interpreter.halted
+    }
+
+    /// The fault that stopped the program, if any
+    pub fn fault(&self) -> Option<Fault> {
+        self.interpreter.fault
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.interpreter.pc
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.interpreter.registers
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.interpreter.zero
+    }
+
+    pub fn carry_flag(&self) -> bool {
+        self.interpreter.carry
+    }
+
+    pub fn memory(&self) -> &[u8; 256] {
+        &self.interpreter.memory
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.interpreter.call_stack
+    }
+
+    /// Total number of instructions executed, including before any resets
+    pub fn instruction_count(&self) -> usize {
+        self.interpreter.instruction_count
+    }
+
+    pub fn io(&self) -> &IoBus {
+        &self.interpreter.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut IoBus {
+        &mut self.interpreter.io
+    }
+
+    pub fn into_io(self) -> IoBus {
+        self.interpreter.io
+    }
+
+    /// The screen as last pushed by the program, indexed `[y][x]` with y = 0 at the bottom
+    pub fn screen(&self) -> PixelBuffer {
+        self.interpreter.io.state.panel.lock().unwrap().screen
+    }
+
+    /// The text on the character display, as last pushed by the program
+    pub fn text(&self) -> String {
+        self.interpreter.io.state.panel.lock().unwrap().character_display.iter().collect()
+    }
+
+    /// The value on the number display, if it is showing
+    pub fn number_display(&self) -> Option<i16> {
+        let panel = self.interpreter.io.state.panel.lock().unwrap();
+        if !panel.show_number_display {
+            return None;
+        }
+
+        Some(match panel.number_display_settings {
+            NumberDisplaySettings::TwosCompliment => panel.number_display as i8 as i16,
+            NumberDisplaySettings::Unsigned => panel.number_display as i16,
+        })
+    }
+
+    /// Presses or releases a controller button
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.interpreter.io.state.panel.lock().unwrap().controller.set(button, pressed);
+    }
+}
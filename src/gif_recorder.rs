@@ -0,0 +1,87 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::{Context, Result};
+use gif::{Encoder, Frame, Repeat};
+
+use crate::{interface::PixelBuffer, screenshot::{self, ScreenshotOptions}};
+
+/// Shortest delay between frames in hundredths of a second. Most viewers slow down anything faster.
+const MIN_DELAY: u64 = 2;
+/// How long the last frame is shown before the animation loops
+const LAST_FRAME_DELAY: u64 = 100;
+
+/// Encodes every screen push into an animated GIF.
+///
+/// Frames are timed by the instruction count at which they were pushed, played back at `clock_hz` instructions per
+/// second, so recordings don't depend on how fast the host runs the program. Frames pushed less than [`MIN_DELAY`]
+/// apart are merged.
+pub struct GifRecorder {
+    encoder: Encoder<BufWriter<File>>,
+    options: ScreenshotOptions,
+    clock_hz: u64,
+    /// The latest frame and the time it was pushed, written once the next frame gives its duration
+    pending: Option<(u64, PixelBuffer)>,
+    /// First error while encoding, reported when the recording is finished
+    error: Option<anyhow::Error>,
+}
+
+impl GifRecorder {
+    pub fn create(path: &Path, options: ScreenshotOptions, clock_hz: u64) -> Result<Self> {
+        screenshot::check_scale(options.scale)?;
+        // GIF frames are at most 65535 pixels wide
+        let size = 32u32.checked_mul(options.scale.max(1)).and_then(|size| u16::try_from(size).ok())
+            .with_context(|| format!("Screenshot scale {} is too large for a GIF, the most is {}", options.scale, u16::MAX / 32))?;
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+        let palette = [options.off, options.on].concat();
+        let mut encoder = Encoder::new(BufWriter::new(file), size, size, &palette)?;
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        Ok(Self {
+            encoder,
+            options,
+            clock_hz: clock_hz.max(1),
+            pending: None,
+            error: None,
+        })
+    }
+
+    pub fn push(&mut self, instruction_count: usize, screen: &PixelBuffer) {
+        let time = instruction_count as u64 * 100 / self.clock_hz;
+
+        match &mut self.pending {
+            Some((_, pending)) if pending == screen => {},
+            Some((pending_time, pending)) if time.saturating_sub(*pending_time) < MIN_DELAY => *pending = *screen,
+            _ => {
+                if let Some((pending_time, pending)) = self.pending.replace((time, *screen)) {
+                    self.write_frame(&pending, time.saturating_sub(pending_time));
+                }
+            },
+        }
+    }
+
+    /// Writes the last frame and flushes the file
+    pub fn finish(mut self) -> Result<()> {
+        if let Some((_, pending)) = self.pending.take() {
+            self.write_frame(&pending, LAST_FRAME_DELAY);
+        }
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+
+        self.encoder.into_inner()?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, screen: &PixelBuffer, delay: u64) {
+        let rows = screenshot::scaled_rows(screen, self.options.scale);
+        let pixels = rows.iter().flatten().map(|&pixel| pixel as u8).collect::<Vec<_>>();
+
+        let mut frame = Frame::from_indexed_pixels(rows[0].len() as u16, rows.len() as u16, pixels, None);
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+
+        if let Err(err) = self.encoder.write_frame(&frame) {
+            self.error.get_or_insert(err.into());
+        }
+    }
+}
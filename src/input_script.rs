@@ -0,0 +1,123 @@
+use std::{fs::{self, File}, io::{BufWriter, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use crate::assembler;
+
+/// When an input event takes effect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventTime {
+    /// Once the given number of instructions have been executed
+    Instruction(usize),
+    /// Once the screen has been pushed the given number of times
+    Frame(usize),
+}
+
+/// Deterministic controller input, replayed instead of the keyboard.
+///
+/// The file has one `<time> <controller byte>` event per line, where the time is an instruction count (`1500`) or a
+/// frame number prefixed with `f` (`f30`), and the byte can be written in decimal, hex (`0x`) or binary (`0b`).
+/// Events must be in chronological order. Blank lines and lines starting with `#` are ignored.
+pub struct InputScript {
+    events: Vec<(EventTime, u8)>,
+    next: usize,
+    value: u8,
+}
+
+impl InputScript {
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path).with_context(|| format!("Failed to read input script {}", path.display()))?;
+        Self::parse(&src).with_context(|| format!("Invalid input script {}", path.display()))
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut events = Vec::new();
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let [time, value] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                bail!("line {}: expected `<time> <controller byte>`", i + 1);
+            };
+
+            let time = match time.strip_prefix('f') {
+                Some(frame) => frame.parse().map(EventTime::Frame),
+                None => time.parse().map(EventTime::Instruction),
+            }.with_context(|| format!("line {}: invalid time `{time}`", i + 1))?;
+
+            let Some(value) = assembler::parse_number(value).and_then(|v| u8::try_from(v).ok()) else {
+                bail!("line {}: invalid controller byte `{value}`", i + 1);
+            };
+
+            events.push((time, value));
+        }
+
+        Ok(Self::from_events(events))
+    }
+
+    /// Creates a script from events in chronological order
+    pub fn from_events(events: Vec<(EventTime, u8)>) -> Self {
+        Self {
+            events,
+            next: 0,
+            value: 0,
+        }
+    }
+
+    /// The controller byte at the given point in the run
+    pub fn value_at(&mut self, instruction_count: usize, frame: usize) -> u8 {
+        // Start again from the beginning if the machine has been rewound to before the last event
+        let rewound = self.next.checked_sub(1).is_some_and(|last| match self.events[last].0 {
+            EventTime::Instruction(n) => instruction_count < n,
+            EventTime::Frame(n) => frame < n,
+        });
+        if rewound {
+            self.next = 0;
+            self.value = 0;
+        }
+
+        while let Some(&(time, value)) = self.events.get(self.next) {
+            let reached = match time {
+                EventTime::Instruction(n) => instruction_count >= n,
+                EventTime::Frame(n) => frame >= n,
+            };
+            if !reached {
+                break;
+            }
+
+            self.value = value;
+            self.next += 1;
+        }
+
+        self.value
+    }
+}
+
+/// Logs the controller byte every time the program sees it change, in the format read by [`InputScript`]
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+    last_value: u8,
+}
+
+impl InputRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "# <instruction count> <controller byte>")?;
+
+        Ok(Self {
+            writer,
+            last_value: 0,
+        })
+    }
+
+    pub fn record(&mut self, instruction_count: usize, value: u8) {
+        if value != self.last_value {
+            writeln!(self.writer, "{instruction_count} {value:#010b}").unwrap();
+            self.last_value = value;
+        }
+    }
+}
@@ -1,29 +1,62 @@
-use std::sync::Mutex;
+use std::{ops::RangeInclusive, path::PathBuf, sync::Arc};
 
-use arrayvec::ArrayString;
+use anyhow::{bail, Result};
+use arrayvec::ArrayVec;
 use bitvec::{array::BitArray, order::Lsb0};
-use once_cell::sync::Lazy;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    device::{BusState, CharacterEncoding, ControllerDevice, Device, NumberDevice, RngDevice, ScreenDevice, TextDevice},
+    save_state::{StateReader, StateWriter},
+};
 
 pub type PixelBuffer = [[bool; 32]; 32];
 
-static RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| Mutex::new(StdRng::from_entropy()));
-static PIXEL_BUFFER: Lazy<Mutex<PixelBuffer>> = Lazy::new(|| Mutex::new([[false; 32]; 32]));
-pub static SCREEN_BUFFER: Lazy<Mutex<PixelBuffer>> = Lazy::new(|| Mutex::new([[false; 32]; 32]));
-pub static SCREEN_BUFFER_DIRTY: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+/// Number of characters the character display can show
+pub const CHARACTER_DISPLAY_WIDTH: usize = 10;
+
+pub type CharacterLine = ArrayVec<char, CHARACTER_DISPLAY_WIDTH>;
+
+/// Packs a pixel buffer into 128 bytes, row by row with 8 pixels per byte
+pub fn save_pixel_buffer(pixels: &PixelBuffer, state: &mut StateWriter) {
+    for row in pixels {
+        for chunk in row.chunks(8) {
+            state.u8(chunk.iter().enumerate().fold(0, |byte, (i, &pixel)| byte | (pixel as u8) << i));
+        }
+    }
+}
+
+pub fn load_pixel_buffer(state: &mut StateReader) -> Result<PixelBuffer> {
+    let mut pixels = [[false; 32]; 32];
+    for row in &mut pixels {
+        for chunk in row.chunks_mut(8) {
+            let byte = state.u8()?;
+            for (i, pixel) in chunk.iter_mut().enumerate() {
+                *pixel = byte & (1 << i) != 0;
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+pub fn save_character_line(line: &CharacterLine, state: &mut StateWriter) {
+    state.u8(line.len() as u8);
+    for &c in line {
+        state.u32(c as u32);
+    }
+}
 
-pub static CONTROLLER_INFO: Lazy<Mutex<ControllerInfo>> = Lazy::new(|| Mutex::new(ControllerInfo::default()));
-static CHARACTER_BUFFER: Lazy<Mutex<ArrayString<20>>> = Lazy::new(|| Mutex::new(ArrayString::default()));
-pub static CHARACTER_DISPLAY: Lazy<Mutex<ArrayString<20>>> = Lazy::new(|| Mutex::new(ArrayString::default()));
-pub static CHARACTER_DISPLAY_DIRTY: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+pub fn load_character_line(state: &mut StateReader) -> Result<CharacterLine> {
+    let len = state.u8()?;
+    if len as usize > CHARACTER_DISPLAY_WIDTH {
+        bail!("Character line is {len} characters long");
+    }
 
-pub static NUMBER_DISPLAY: Lazy<Mutex<u8>> = Lazy::new(|| Mutex::new(0));
-pub static NUMBER_DISPLAY_DIRTY: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
-pub static SHOW_NUMBER_DISPLAY: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
-pub static NUMBER_DISPLAY_SETTINGS: Lazy<Mutex<NumberDisplaySettings>> = Lazy::new(|| Mutex::new(NumberDisplaySettings::TwosCompliment));
+    (0..len).map(|_| Ok(char::from_u32(state.u32()?).unwrap_or(' '))).collect()
+}
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub enum NumberDisplaySettings {
+    #[default]
     TwosCompliment,
     Unsigned
 }
@@ -31,91 +64,257 @@ pub enum NumberDisplaySettings {
 #[derive(Default)]
 pub struct ControllerInfo(BitArray<[u8; 1], Lsb0>);
 
-pub unsafe extern "C" fn on_mem_read(mem: *mut u8, addr: usize) {
-    // println!("Read {addr}");
-    match addr {
-        244 => { // Load Pixel at (Pixel X, Pixel Y)
-            let (pixel_x, pixel_y) = get_pixel_coords(mem);
+impl ControllerInfo {
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        self.0.set(button as usize, pressed);
+    }
+
+    pub fn value(&self) -> u8 {
+        self.0.data[0]
+    }
 
-            let pixel_buffer = PIXEL_BUFFER.lock().unwrap();
+    pub fn set_value(&mut self, value: u8) {
+        self.0.data[0] = value;
+    }
+}
 
-            *mem.add(addr) = pixel_buffer[pixel_y][pixel_x] as u8;
-        }
-        254 => { // Load a random 8 bit number
-            let mut rng = RNG.lock().unwrap();
-            *mem.add(addr) = rng.gen::<u8>();
-        }
-        255 => { // Load controller info
-            let info = CONTROLLER_INFO.lock().unwrap();
-            *mem.add(addr) = info.0.data[0];
-        }
-        _ => {}
+/// Controller buttons, numbered by their bit in the controller byte at address 255
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    Left,
+    Down,
+    Right,
+    Up,
+    B,
+    A,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "left" => Button::Left,
+            "down" => Button::Down,
+            "right" => Button::Right,
+            "up" => Button::Up,
+            "b" => Button::B,
+            "a" => Button::A,
+            "select" => Button::Select,
+            "start" => Button::Start,
+            _ => return None,
+        })
     }
 }
 
-pub unsafe extern "C" fn on_mem_write(mem: *mut u8, addr: usize) {
-    // println!("Wrote to {addr}");
-    match addr {
-        242 => { // Draw pixel at (Pixel X, Pixel Y) to buffer
-            let (pixel_x, pixel_y) = get_pixel_coords(mem);
+/// The displays and controller of a machine, shared with the UI thread.
+/// The dirty flags mark what has changed since the UI last drew it.
+#[derive(Default)]
+pub struct Panel {
+    pub screen: PixelBuffer,
+    pub screen_dirty: bool,
+    pub character_display: CharacterLine,
+    pub character_display_dirty: bool,
+    pub number_display: u8,
+    pub show_number_display: bool,
+    pub number_display_settings: NumberDisplaySettings,
+    pub number_display_dirty: bool,
+    pub controller: ControllerInfo,
+    /// A save state requested from the UI, carried out by the emulator thread
+    pub state_request: Option<StateRequest>,
+    /// A message for the UI's status line, such as the result of a state request
+    pub status: Option<String>,
+    /// Set by the UI while the rewind key is held
+    pub rewinding: bool,
+    /// Set by the UI to run at full speed regardless of `--clock-hz`
+    pub unthrottled: bool,
+}
 
-            let mut pixel_buffer = PIXEL_BUFFER.lock().unwrap();
-            pixel_buffer[pixel_y][pixel_x] = true;
-        }
-        243 => { // Clear pixel at (Pixel X, Pixel Y) to buffer
-            let (pixel_x, pixel_y) = get_pixel_coords(mem);
+impl Panel {
+    fn save_state(&self, state: &mut StateWriter) {
+        save_pixel_buffer(&self.screen, state);
+        save_character_line(&self.character_display, state);
+        state.u8(self.number_display);
+        state.bool(self.show_number_display);
+        state.u8(self.number_display_settings as u8);
+        state.u8(self.controller.value());
+    }
 
-            let mut pixel_buffer = PIXEL_BUFFER.lock().unwrap();
-            pixel_buffer[pixel_y][pixel_x] = false;
-        }
-        245 => { // Push screen buffer
-            let pixel_buffer = PIXEL_BUFFER.lock().unwrap();
-            let mut screen_buffer = SCREEN_BUFFER.lock().unwrap();
-            *screen_buffer = *pixel_buffer;
-            *SCREEN_BUFFER_DIRTY.lock().unwrap() = true;
+    fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        self.screen = load_pixel_buffer(state)?;
+        self.character_display = load_character_line(state)?;
+        self.number_display = state.u8()?;
+        self.show_number_display = state.bool()?;
+        self.number_display_settings = match state.u8()? {
+            0 => NumberDisplaySettings::TwosCompliment,
+            _ => NumberDisplaySettings::Unsigned,
+        };
+        self.controller.set_value(state.u8()?);
+
+        self.screen_dirty = true;
+        self.character_display_dirty = true;
+        self.number_display_dirty = true;
+        Ok(())
+    }
+}
+
+/// Save state operations the UI can ask the emulator thread to perform
+#[derive(Clone, Debug)]
+pub enum StateRequest {
+    Save(PathBuf),
+    Load(PathBuf),
+}
+
+/// The memory-mapped devices of a single machine, looked up by address.
+///
+/// Compiled programs reach it through the context pointer passed to [`on_mem_read`] and [`on_mem_write`].
+pub struct IoBus {
+    pub state: BusState,
+    devices: Vec<Box<dyn Device>>,
+    /// Index into `devices` of the device attached to each address
+    map: [Option<usize>; 256],
+    seed: u64,
+}
+
+impl IoBus {
+    /// Creates a bus with the BatPU-2's devices attached, with the random number port seeded from `seed`
+    pub fn new(seed: u64) -> Self {
+        let mut io = Self::empty(seed);
+        io.attach(240..=246, ScreenDevice::default());
+        io.attach(247..=249, TextDevice::new(CharacterEncoding::Batpu));
+        io.attach(250..=253, NumberDevice);
+        io.attach(254..=254, RngDevice::new(seed));
+        io.attach(255..=255, ControllerDevice);
+        io
+    }
+
+    /// Creates a bus without any devices, where every address behaves like memory
+    pub fn empty(seed: u64) -> Self {
+        Self {
+            state: BusState {
+                panel: Arc::default(),
+                instruction_count: 0,
+                frame_count: 0,
+                input_script: None,
+                input_recorder: None,
+                input_log: None,
+                gif_recorder: None,
+                throttle: None,
+            },
+            devices: Vec::new(),
+            map: [None; 256],
+            seed,
         }
-        246 => { // Clear screen buffer
-            let mut pixel_buffer = PIXEL_BUFFER.lock().unwrap();
-            *pixel_buffer = [[false; 32]; 32];
+    }
+
+    /// Attaches `device` to `addrs`, replacing whatever was attached there before
+    pub fn attach(&mut self, addrs: RangeInclusive<u8>, device: impl Device + 'static) {
+        self.devices.push(Box::new(device));
+        for addr in addrs {
+            self.map[addr as usize] = Some(self.devices.len() - 1);
         }
-        247 => { // Write character to buffer
-            let mut char_buffer = CHARACTER_BUFFER.lock().unwrap();
-            char_buffer.push(*mem.add(addr) as char);
+    }
+
+    /// Whether a device is attached to `addr`
+    pub fn is_device(&self, addr: u8) -> bool {
+        self.map[addr as usize].is_some()
+    }
+
+    /// The seed the bus was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Resets every device, at the start of every run
+    pub fn reset(&mut self) {
+        for device in &mut self.devices {
+            device.reset();
         }
-        248 => { // Push character buffer
-            let char_buffer = CHARACTER_BUFFER.lock().unwrap();
-            let mut char_display = CHARACTER_DISPLAY.lock().unwrap();
-            *char_display = *char_buffer;
-            *CHARACTER_DISPLAY_DIRTY.lock().unwrap() = true;
+    }
+
+    /// Handles a read of `addr`, updating it in `mem` first if it belongs to a device
+    pub fn read(&mut self, mem: &mut [u8; 256], addr: usize) {
+        self.tick();
+        if let Some(index) = self.map[addr] {
+            if let Some(value) = self.devices[index].read(addr as u8, &mut self.state) {
+                mem[addr] = value;
+            }
         }
-        249 => { // Clear character buffer
-            let mut char_buffer = CHARACTER_BUFFER.lock().unwrap();
-            char_buffer.clear();
+    }
+
+    /// Handles a write to `addr`, after the value has been stored in `mem`
+    pub fn write(&mut self, mem: &mut [u8; 256], addr: usize) {
+        self.tick();
+        if let Some(index) = self.map[addr] {
+            self.devices[index].write(addr as u8, mem[addr], &mut self.state);
         }
-        250 => { // Show number display
-            *NUMBER_DISPLAY.lock().unwrap() = *mem.add(addr);
-            *SHOW_NUMBER_DISPLAY.lock().unwrap() = true;
-            *NUMBER_DISPLAY_DIRTY.lock().unwrap() = true;
+    }
+
+    /// Writes the panel and every device, each device in a length-prefixed block in the order they were attached
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.state.frame_count as u64);
+        self.state.panel.lock().unwrap().save_state(state);
+
+        state.u16(self.devices.len() as u16);
+        for device in &self.devices {
+            let mut device_state = StateWriter::default();
+            device.save_state(&mut device_state);
+            let device_state = device_state.into_bytes();
+            state.u32(device_state.len() as u32);
+            state.bytes(&device_state);
         }
-        251 => { // Clear number display
-            *SHOW_NUMBER_DISPLAY.lock().unwrap() = false;
-            *NUMBER_DISPLAY_DIRTY.lock().unwrap() = true;
+    }
+
+    /// Restores a state written by [`save_state`](Self::save_state) into a bus with the same devices
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        let frame_count = state.u64()? as usize;
+        let mut panel = Panel::default();
+        panel.load_state(state)?;
+
+        let count = state.u16()? as usize;
+        if count != self.devices.len() {
+            bail!("Save state has {count} devices, but the bus has {}", self.devices.len());
         }
-        252 => { // Interpret number as 2s comp [-128, 127]
-            *NUMBER_DISPLAY_SETTINGS.lock().unwrap() = NumberDisplaySettings::TwosCompliment;
-            *NUMBER_DISPLAY_DIRTY.lock().unwrap() = true;
+        let device_states = (0..count)
+            .map(|_| {
+                let len = state.u32()? as usize;
+                state.bytes(len)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (device, device_state) in self.devices.iter_mut().zip(device_states) {
+            let mut device_state = StateReader::new(device_state);
+            device.load_state(&mut device_state)?;
+            device_state.finish()?;
         }
-        253 => { // Interpret number as unsigned int [0, 255]
-            *NUMBER_DISPLAY_SETTINGS.lock().unwrap() = NumberDisplaySettings::Unsigned;
-            *NUMBER_DISPLAY_DIRTY.lock().unwrap() = true;
+
+        self.state.frame_count = frame_count;
+        let mut current = self.state.panel.lock().unwrap();
+        // Pending requests and messages belong to the UI, not the machine
+        panel.state_request = current.state_request.take();
+        panel.status = current.status.take();
+        panel.rewinding = current.rewinding;
+        panel.unthrottled = current.unthrottled;
+        *current = panel;
+        Ok(())
+    }
+
+    fn tick(&mut self) {
+        self.state.pace();
+        for device in &mut self.devices {
+            device.tick(&mut self.state);
         }
-        _ => {}
     }
 }
 
-unsafe fn get_pixel_coords(mem: *mut u8) -> (usize, usize) {
-    let pixel_x = (*mem.offset(240) & 0b11111) as usize;
-    let pixel_y = (*mem.offset(241) & 0b11111) as usize;
+/// # Safety
+/// `mem` must point to the 256 byte memory space of the running program and `io` to its I/O bus
+pub unsafe extern "C" fn on_mem_read(mem: *mut u8, addr: usize, io: *mut IoBus) {
+    (*io).read(&mut *mem.cast(), addr);
+}
 
-    (pixel_x, pixel_y)
-}
\ No newline at end of file
+/// # Safety
+/// `mem` must point to the 256 byte memory space of the running program and `io` to its I/O bus
+pub unsafe extern "C" fn on_mem_write(mem: *mut u8, addr: usize, io: *mut IoBus) {
+    (*io).write(&mut *mem.cast(), addr);
+}
@@ -0,0 +1,462 @@
+use std::{fmt, mem};
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::{
+    interface::IoBus,
+    save_state::{StateReader, StateWriter},
+    transpiler::{Condition, Instruction},
+};
+
+pub const CALL_STACK_SIZE: usize = 16;
+
+/// Number of words in the instruction ROM. The 10-bit program counter wraps to 0 after the last one.
+pub const ROM_SIZE: usize = 1024;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CallStackPolicy {
+    /// Wrap around like the hardware, overwriting or reusing the oldest entries
+    Wrap,
+    /// Halt the program and report the instruction that caused it
+    #[default]
+    Halt,
+    /// Pause in the debugger before the instruction (interpreter only, runs without the GUI)
+    Trap
+}
+
+/// Settings shared by every backend that change how a program executes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExecutionOptions {
+    pub call_stack_policy: CallStackPolicy,
+    /// Report executing past the last loaded instruction as an error instead of running the `nop` padding
+    pub strict: bool,
+}
+
+/// The hardware call stack: a fixed number of return addresses in a circular buffer.
+/// Pushing onto a full stack overwrites the oldest entry and popping an empty one wraps around.
+#[derive(Clone)]
+pub struct CallStack {
+    entries: [u16; CALL_STACK_SIZE],
+    pointer: usize,
+    depth: usize,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self {
+            entries: [0; CALL_STACK_SIZE],
+            pointer: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn push(&mut self, addr: u16) {
+        self.entries[self.pointer] = addr;
+        self.pointer = (self.pointer + 1) % CALL_STACK_SIZE;
+        self.depth = (self.depth + 1).min(CALL_STACK_SIZE);
+    }
+
+    pub fn pop(&mut self) -> u16 {
+        self.pointer = (self.pointer + CALL_STACK_SIZE - 1) % CALL_STACK_SIZE;
+        self.depth = self.depth.saturating_sub(1);
+        self.entries[self.pointer]
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.depth == CALL_STACK_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.depth == 0
+    }
+
+    /// Return addresses currently on the stack, most recent first
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (1..=self.depth).map(|i| self.entries[(self.pointer + CALL_STACK_SIZE - i) % CALL_STACK_SIZE])
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        for &entry in &self.entries {
+            state.u16(entry);
+        }
+        state.u8(self.pointer as u8);
+        state.u8(self.depth as u8);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Self> {
+        let mut entries = [0; CALL_STACK_SIZE];
+        for entry in &mut entries {
+            *entry = state.u16()? % ROM_SIZE as u16;
+        }
+
+        Ok(Self {
+            entries,
+            pointer: state.u8()? as usize % CALL_STACK_SIZE,
+            depth: (state.u8()? as usize).min(CALL_STACK_SIZE),
+        })
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    CallStackOverflow = 1,
+    CallStackUnderflow = 2,
+    PastEndOfProgram = 3,
+}
+
+/// An instruction that could not be executed under the configured policy
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub pc: u16,
+    pub instruction: Instruction,
+}
+
+impl Fault {
+    /// Status returned by compiled programs: 0 if the program halted normally, otherwise `pc << 8 | kind`
+    pub fn status(kind: FaultKind, pc: u16) -> u32 {
+        (pc as u32) << 8 | kind as u32
+    }
+
+    pub fn from_status(status: u64, instructions: &[Instruction]) -> Option<Self> {
+        let kind = match status & 0xFF {
+            1 => FaultKind::CallStackOverflow,
+            2 => FaultKind::CallStackUnderflow,
+            3 => FaultKind::PastEndOfProgram,
+            _ => return None,
+        };
+        let pc = (status >> 8) as u16;
+
+        Some(Self {
+            kind,
+            pc,
+            instruction: instructions.get(pc as usize).copied().unwrap_or(Instruction::Nop),
+        })
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            FaultKind::CallStackOverflow => "Call stack overflow",
+            FaultKind::CallStackUnderflow => "Call stack underflow",
+            FaultKind::PastEndOfProgram => "Executed past the end of the program",
+        };
+        write!(f, "{kind} at {}: {:?}", self.pc, self.instruction)
+    }
+}
+
+/// The CPU state before an instruction executed, passed to observers along with the interpreter after it
+#[derive(Clone, Copy, Debug)]
+pub struct CpuState {
+    pub pc: u16,
+    pub registers: [u8; 16],
+    pub zero: bool,
+    pub carry: bool,
+    pub instruction: Instruction,
+}
+
+/// Watches every instruction the interpreter executes, for traces, coverage and profiling
+pub trait Observer: Send {
+    /// Called after each instruction executes, with the state before it and the interpreter after it
+    fn observe(&mut self, before: &CpuState, interpreter: &Interpreter);
+
+    /// Called once the run is over, to write out any results
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+enum Step {
+    Continue,
+    Halt,
+    Trap,
+}
+
+/// Executes decoded instructions directly, without going through nasm.
+pub struct Interpreter {
+    instructions: Vec<Instruction>,
+    pub memory: [u8; 256],
+    pub registers: [u8; 16],
+    pub pc: u16,
+    pub carry: bool,
+    pub zero: bool,
+    pub call_stack: CallStack,
+    pub options: ExecutionOptions,
+    pub instruction_count: usize,
+    pub halted: bool,
+    /// The fault that stopped execution, if any
+    pub fault: Option<Fault>,
+    pub io: IoBus,
+    /// Notified after every instruction. Stepping is slower while there are any.
+    pub observers: Vec<Box<dyn Observer>>,
+}
+
+impl Interpreter {
+    pub fn new(instructions: Vec<Instruction>, options: ExecutionOptions, io: IoBus) -> Self {
+        Self {
+            instructions,
+            memory: [0; 256],
+            registers: [0; 16],
+            pc: 0,
+            carry: false,
+            zero: false,
+            call_stack: CallStack::new(),
+            options,
+            instruction_count: 0,
+            halted: false,
+            fault: None,
+            io,
+            observers: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.memory = [0; 256];
+        self.registers = [0; 16];
+        self.pc = 0;
+        self.carry = false;
+        self.zero = false;
+        self.call_stack = CallStack::new();
+        self.halted = false;
+        self.fault = None;
+        self.io.reset();
+    }
+
+    /// Writes the CPU state followed by the I/O bus, see [`save_state::save`](crate::save_state::save)
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.pc);
+        state.bytes(&self.registers);
+        state.bytes(&self.memory);
+        state.bool(self.zero);
+        state.bool(self.carry);
+        self.call_stack.save_state(state);
+        state.u64(self.instruction_count as u64);
+        state.bool(self.halted);
+        state.u32(self.fault.map_or(0, |fault| Fault::status(fault.kind, fault.pc)));
+        self.io.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<()> {
+        let pc = state.u16()? % ROM_SIZE as u16;
+        let mut registers: [u8; 16] = state.bytes(16)?.try_into().unwrap();
+        registers[0] = 0;
+        let memory = state.bytes(256)?.try_into().unwrap();
+        let zero = state.bool()?;
+        let carry = state.bool()?;
+        let call_stack = CallStack::load_state(state)?;
+        let instruction_count = state.u64()? as usize;
+        let halted = state.bool()?;
+        let fault = Fault::from_status(state.u32()? as u64, &self.instructions);
+
+        self.io.load_state(state)?;
+
+        self.pc = pc;
+        self.registers = registers;
+        self.memory = memory;
+        self.zero = zero;
+        self.carry = carry;
+        self.call_stack = call_stack;
+        self.instruction_count = instruction_count;
+        self.halted = halted;
+        self.fault = fault;
+        Ok(())
+    }
+
+    /// Finishes and removes every observer, returning the first error
+    pub fn finish_observers(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for mut observer in self.observers.drain(..) {
+            result = result.and(observer.finish());
+        }
+        result
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// The instruction at the PC. The ROM past the end of the loaded program is filled with `nop`s.
+    pub fn current_instruction(&self) -> Instruction {
+        self.instructions.get(self.pc as usize).copied().unwrap_or(Instruction::Nop)
+    }
+
+    /// Runs until the program halts or traps
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Executes a single instruction, returning false once the program has halted or trapped.
+    /// Stepping again after a trap executes the trapping instruction with hardware semantics.
+    pub fn step(&mut self) -> bool {
+        if self.observers.is_empty() {
+            self.step_unobserved()
+        } else {
+            self.observed_step()
+        }
+    }
+
+    fn observed_step(&mut self) -> bool {
+        let before = CpuState {
+            pc: self.pc,
+            registers: self.registers,
+            zero: self.zero,
+            carry: self.carry,
+            instruction: self.current_instruction(),
+        };
+        let count = self.instruction_count;
+
+        let mut observers = mem::take(&mut self.observers);
+        let running = self.step_unobserved();
+        if self.instruction_count != count {
+            for observer in &mut observers {
+                observer.observe(&before, self);
+            }
+        }
+        self.observers = observers;
+        running
+    }
+
+    fn step_unobserved(&mut self) -> bool {
+        match self.execute() {
+            Step::Continue => true,
+            Step::Halt => {
+                self.halted = true;
+                false
+            },
+            Step::Trap => false,
+        }
+    }
+
+    fn execute(&mut self) -> Step {
+        if self.halted {
+            return Step::Halt;
+        }
+
+        let instruction = self.current_instruction();
+
+        if self.options.strict && self.pc as usize >= self.instructions.len() {
+            self.fault = Some(Fault { kind: FaultKind::PastEndOfProgram, pc: self.pc, instruction });
+            return Step::Halt;
+        }
+
+        let resuming = self.fault.take().is_some();
+        let fault_kind = match instruction {
+            Instruction::Cal(_) if self.call_stack.is_full() => Some(FaultKind::CallStackOverflow),
+            Instruction::Ret if self.call_stack.is_empty() => Some(FaultKind::CallStackUnderflow),
+            _ => None,
+        };
+
+        if let (Some(kind), false) = (fault_kind, resuming) {
+            let step = match self.options.call_stack_policy {
+                CallStackPolicy::Wrap => Step::Continue,
+                CallStackPolicy::Halt => Step::Halt,
+                CallStackPolicy::Trap => Step::Trap,
+            };
+
+            if !matches!(step, Step::Continue) {
+                self.fault = Some(Fault { kind, pc: self.pc, instruction });
+                return step;
+            }
+        }
+
+        self.instruction_count += 1;
+        let mut next_pc = (self.pc + 1) % ROM_SIZE as u16;
+
+        match instruction {
+            Instruction::Nop => {},
+            Instruction::Hlt => return Step::Halt,
+            Instruction::Add(a, b, c) => {
+                let (result, carry) = self.reg(a).overflowing_add(self.reg(b));
+                self.set_reg(c, result);
+                self.set_flags(result, carry);
+            },
+            Instruction::Sub(a, b, c) => {
+                let (result, borrow) = self.reg(a).overflowing_sub(self.reg(b));
+                self.set_reg(c, result);
+                self.set_flags(result, !borrow);
+            },
+            Instruction::Nor(a, b, c) => {
+                let result = !(self.reg(a) | self.reg(b));
+                self.set_reg(c, result);
+                self.set_flags(result, false);
+            },
+            Instruction::And(a, b, c) => {
+                let result = self.reg(a) & self.reg(b);
+                self.set_reg(c, result);
+                self.set_flags(result, false);
+            },
+            Instruction::Xor(a, b, c) => {
+                let result = self.reg(a) ^ self.reg(b);
+                self.set_reg(c, result);
+                self.set_flags(result, false);
+            },
+            Instruction::Rsh(a, c) => {
+                let result = self.reg(a) >> 1;
+                self.set_reg(c, result);
+            },
+            Instruction::Ldi(a, i) => self.set_reg(a, i),
+            Instruction::Adi(a, i) => {
+                let (result, carry) = self.reg(a).overflowing_add(i);
+                self.set_reg(a, result);
+                self.set_flags(result, carry);
+            },
+            Instruction::Jmp(addr) => next_pc = addr,
+            Instruction::Brh(ref condition, addr) => {
+                let taken = match condition {
+                    Condition::Equal => self.zero,
+                    Condition::NotEqual => !self.zero,
+                    Condition::GreaterThanOrEqual => self.carry,
+                    Condition::LessThan => !self.carry,
+                };
+
+                if taken {
+                    next_pc = addr;
+                }
+            },
+            Instruction::Cal(addr) => {
+                self.call_stack.push(next_pc);
+                next_pc = addr;
+            },
+            Instruction::Ret => next_pc = self.call_stack.pop(),
+            Instruction::Lod(a, b, o) => {
+                let addr = self.reg(a).wrapping_add_signed(o);
+                self.io.state.instruction_count = self.instruction_count;
+                self.io.read(&mut self.memory, addr as usize);
+                self.set_reg(b, self.memory[addr as usize]);
+            },
+            Instruction::Str(a, b, o) => {
+                let addr = self.reg(a).wrapping_add_signed(o);
+                self.memory[addr as usize] = self.reg(b);
+                self.io.state.instruction_count = self.instruction_count;
+                self.io.write(&mut self.memory, addr as usize);
+            },
+        }
+
+        self.pc = next_pc;
+        Step::Continue
+    }
+
+    fn reg(&self, reg: u8) -> u8 {
+        self.registers[reg as usize]
+    }
+
+    fn set_reg(&mut self, reg: u8, value: u8) {
+        // r0 is hardwired to zero
+        if reg != 0 {
+            self.registers[reg as usize] = value;
+        }
+    }
+
+    fn set_flags(&mut self, result: u8, carry: bool) {
+        self.zero = result == 0;
+        self.carry = carry;
+    }
+}
@@ -1,4 +1,11 @@
     ; cal
-    sub rsp, 8
-    call {l}
-    add rsp, 8
\ No newline at end of file
+{check}    mov rcx, [rel call_stack_pointer]
+    lea rdx, [rel call_stack]
+    lea rax, [rel {r}]
+    mov [rdx + rcx*8], rax
+    inc rcx
+    and rcx, 15
+    mov [rel call_stack_pointer], rcx
+    inc qword [rel call_stack_depth]
+    jmp {l}
+{r}:
@@ -0,0 +1,3 @@
+    mov eax, {status}
+    cmp qword [rel call_stack_depth], 16
+    jae _fault
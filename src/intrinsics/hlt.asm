@@ -1,3 +1,4 @@
     ; hlt
+    xor eax, eax
     mov rsp, [ret_addr]
     ret
\ No newline at end of file
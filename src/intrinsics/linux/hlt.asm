@@ -0,0 +1,3 @@
+    ; hlt
+    xor eax, eax
+    jmp _halt
\ No newline at end of file
@@ -0,0 +1,13 @@
+    ; lod
+    mov cl, [r13 + {a}]
+    add cl, {o}
+    movzx rsi, cl
+    push rsi
+    sub rsp, 8
+    mov rdi, r12
+    mov rdx, [io_context]
+    call [mem_read_callback]
+    add rsp, 8
+    pop rsi
+    mov cl, [r12 + rsi]
+    mov {dest}, cl
\ No newline at end of file
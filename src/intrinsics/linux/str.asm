@@ -0,0 +1,9 @@
+    ; str
+    mov cl, [r13 + {a}]
+    add cl, {o}
+    movzx rsi, cl
+    mov dl, [r13 + {b}]
+    mov [r12 + rsi], dl
+    mov rdi, r12
+    mov rdx, [io_context]
+    call [mem_write_callback]
\ No newline at end of file
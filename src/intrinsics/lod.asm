@@ -5,6 +5,7 @@
     sub rsp, 8
     push rdx
     mov rcx, r12
+    mov r8, [io_context]
     call [mem_read_callback]
     pop rdx
     add rsp, 8
@@ -0,0 +1,3 @@
+    ; past the end of the program
+    mov eax, {status}
+    jmp _fault
\ No newline at end of file
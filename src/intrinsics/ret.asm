@@ -1,2 +1,8 @@
     ; ret
-    ret
\ No newline at end of file
+{check}    mov rcx, [rel call_stack_pointer]
+    dec rcx
+    and rcx, 15
+    mov [rel call_stack_pointer], rcx
+    dec qword [rel call_stack_depth]
+    lea rdx, [rel call_stack]
+    jmp [rdx + rcx*8]
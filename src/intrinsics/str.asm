@@ -5,4 +5,5 @@
     mov bl, [r13 + {b}]
     mov [r12 + rdx], bl    
     mov rcx, r12
+    mov r8, [io_context]
     call [mem_write_callback]
@@ -0,0 +1,445 @@
+use std::ptr;
+
+use crate::{backend::CompiledMain, interpreter::{CallStackPolicy, ExecutionOptions, Fault, FaultKind, CALL_STACK_SIZE, ROM_SIZE}, transpiler::{Condition, Instruction}};
+
+/// Size of the frame `_main` reserves below the saved registers. Holds the read callback, write callback,
+/// instruction count pointer, I/O context and the call stack, padded to keep the stack 16 byte aligned.
+const FRAME_SIZE: u32 = 184;
+
+const READ_CALLBACK: u8 = 0;
+const WRITE_CALLBACK: u8 = 8;
+const INSTRUCTION_COUNT: u8 = 16;
+const CALL_STACK_POINTER: u8 = 24;
+const CALL_STACK_DEPTH: u8 = 32;
+/// Passed to the memory handlers along with the memory space
+const IO_CONTEXT: u8 = 40;
+/// Native return addresses, used as a circular buffer like the hardware call stack
+const CALL_STACK: u8 = 48;
+
+/// Machine code for a program, compiled in-process and mapped as executable memory.
+///
+/// Register usage mirrors the nasm intrinsics: r12 holds the memory space, r13 the registers, r14 the carry flag
+/// and r15 the zero flag. rbx points to the frame holding the callbacks and the call stack.
+pub struct JitProgram {
+    code: ExecutableBuffer,
+}
+
+impl JitProgram {
+    pub fn compile(instructions: &[Instruction], count_instructions: bool, options: ExecutionOptions) -> Self {
+        let mut emitter = Emitter {
+            count_instructions,
+            check_call_stack: options.call_stack_policy != CallStackPolicy::Wrap,
+            ..Default::default()
+        };
+        emitter.prologue();
+
+        // Code is emitted for the whole ROM, with `nop`s after the loaded program
+        let mut instruction_offsets = Vec::with_capacity(ROM_SIZE);
+        for addr in 0..ROM_SIZE as u16 {
+            instruction_offsets.push(emitter.code.len());
+
+            match instructions.get(addr as usize) {
+                Some(instruction) => emitter.instruction(addr, instruction),
+                None if options.strict => {
+                    emitter.emit(&[0xB8]); // mov eax, status
+                    emitter.emit(&Fault::status(FaultKind::PastEndOfProgram, addr).to_le_bytes());
+                    emitter.emit(&[0xE9]); // jmp fault
+                    emitter.fault_fixups.push(emitter.code.len());
+                    emitter.emit(&[0; 4]);
+                },
+                None => emitter.instruction(addr, &Instruction::Nop),
+            }
+        }
+
+        // The program counter wraps around after the last address
+        emitter.emit(&[0xE9]); // jmp 0
+        emitter.fixup(0);
+
+        let halt_offset = emitter.code.len();
+        emitter.emit(&[0x31, 0xC0]); // xor eax, eax
+        let fault_offset = emitter.code.len();
+        emitter.epilogue();
+
+        for (patch_offset, addr) in emitter.fixups.drain(..).collect::<Vec<_>>() {
+            let target = instruction_offsets[addr as usize % ROM_SIZE];
+            emitter.patch_rel32(patch_offset, target);
+        }
+
+        for patch_offset in emitter.halt_fixups.drain(..).collect::<Vec<_>>() {
+            emitter.patch_rel32(patch_offset, halt_offset);
+        }
+
+        for patch_offset in emitter.fault_fixups.drain(..).collect::<Vec<_>>() {
+            emitter.patch_rel32(patch_offset, fault_offset);
+        }
+
+        Self {
+            code: ExecutableBuffer::new(&emitter.code),
+        }
+    }
+
+    pub fn main(&self) -> CompiledMain {
+        unsafe { std::mem::transmute::<*const u8, CompiledMain>(self.code.ptr) }
+    }
+}
+
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+    /// Whether to increment the instruction counter before every instruction
+    count_instructions: bool,
+    /// Whether `cal` and `ret` fault on overflow and underflow instead of wrapping
+    check_call_stack: bool,
+    /// Offsets of rel32 operands that jump to a BatPU-2 address
+    fixups: Vec<(usize, u16)>,
+    /// Offsets of rel32 operands that jump to the epilogue
+    halt_fixups: Vec<usize>,
+    /// Offsets of rel32 operands that jump to the epilogue, returning the fault status in eax
+    fault_fixups: Vec<usize>,
+}
+
+impl Emitter {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn patch_rel32(&mut self, patch_offset: usize, target: usize) {
+        let rel = target as i64 - (patch_offset as i64 + 4);
+        self.code[patch_offset..patch_offset + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    fn prologue(&mut self) {
+        self.emit(&[0x53]); // push rbx
+        self.emit(&[0x55]); // push rbp
+        if cfg!(windows) {
+            self.emit(&[0x57]); // push rdi
+            self.emit(&[0x56]); // push rsi
+        }
+        self.emit(&[0x41, 0x54]); // push r12
+        self.emit(&[0x41, 0x55]); // push r13
+        self.emit(&[0x41, 0x56]); // push r14
+        self.emit(&[0x41, 0x57]); // push r15
+        self.emit(&[0x48, 0x81, 0xEC]); // sub rsp, FRAME_SIZE
+        self.emit(&FRAME_SIZE.to_le_bytes());
+        self.emit(&[0x48, 0x89, 0xE3]); // mov rbx, rsp
+
+        if cfg!(windows) {
+            self.emit(&[0x49, 0x89, 0xCC]); // mov r12, rcx
+            self.emit(&[0x49, 0x89, 0xD5]); // mov r13, rdx
+            self.emit(&[0x4C, 0x89, 0x43, READ_CALLBACK]); // mov [rbx + READ_CALLBACK], r8
+            self.emit(&[0x4C, 0x89, 0x4B, WRITE_CALLBACK]); // mov [rbx + WRITE_CALLBACK], r9
+
+            // The fifth and sixth arguments sit above the return address, the shadow space and everything pushed above
+            let stack_arg = FRAME_SIZE + 8 * 8 + 40;
+            self.emit(&[0x48, 0x8B, 0x83]); // mov rax, [rbx + stack_arg]
+            self.emit(&stack_arg.to_le_bytes());
+            self.emit(&[0x48, 0x89, 0x43, INSTRUCTION_COUNT]); // mov [rbx + INSTRUCTION_COUNT], rax
+            self.emit(&[0x48, 0x8B, 0x83]); // mov rax, [rbx + stack_arg + 8]
+            self.emit(&(stack_arg + 8).to_le_bytes());
+            self.emit(&[0x48, 0x89, 0x43, IO_CONTEXT]); // mov [rbx + IO_CONTEXT], rax
+        } else {
+            self.emit(&[0x49, 0x89, 0xFC]); // mov r12, rdi
+            self.emit(&[0x49, 0x89, 0xF5]); // mov r13, rsi
+            self.emit(&[0x48, 0x89, 0x53, READ_CALLBACK]); // mov [rbx + READ_CALLBACK], rdx
+            self.emit(&[0x48, 0x89, 0x4B, WRITE_CALLBACK]); // mov [rbx + WRITE_CALLBACK], rcx
+            self.emit(&[0x4C, 0x89, 0x43, INSTRUCTION_COUNT]); // mov [rbx + INSTRUCTION_COUNT], r8
+            self.emit(&[0x4C, 0x89, 0x4B, IO_CONTEXT]); // mov [rbx + IO_CONTEXT], r9
+        }
+
+        self.emit(&[0x45, 0x31, 0xF6]); // xor r14d, r14d
+        self.emit(&[0x45, 0x31, 0xFF]); // xor r15d, r15d
+
+        // Empty call stack entries return to address 0
+        self.emit(&[0x48, 0x8D, 0x05]); // lea rax, [addr 0]
+        self.fixup(0);
+        for i in 0..CALL_STACK_SIZE as u32 {
+            self.emit(&[0x48, 0x89, 0x83]); // mov [rbx + CALL_STACK + 8 * i], rax
+            self.emit(&(CALL_STACK as u32 + 8 * i).to_le_bytes());
+        }
+        self.emit(&[0x48, 0xC7, 0x43, CALL_STACK_POINTER, 0, 0, 0, 0]); // mov qword [rbx + CALL_STACK_POINTER], 0
+        self.emit(&[0x48, 0xC7, 0x43, CALL_STACK_DEPTH, 0, 0, 0, 0]); // mov qword [rbx + CALL_STACK_DEPTH], 0
+    }
+
+    fn epilogue(&mut self) {
+        self.emit(&[0x48, 0x8D, 0xA3]); // lea rsp, [rbx + FRAME_SIZE]
+        self.emit(&FRAME_SIZE.to_le_bytes());
+        self.emit(&[0x41, 0x5F]); // pop r15
+        self.emit(&[0x41, 0x5E]); // pop r14
+        self.emit(&[0x41, 0x5D]); // pop r13
+        self.emit(&[0x41, 0x5C]); // pop r12
+        if cfg!(windows) {
+            self.emit(&[0x5E]); // pop rsi
+            self.emit(&[0x5F]); // pop rdi
+        }
+        self.emit(&[0x5D]); // pop rbp
+        self.emit(&[0x5B]); // pop rbx
+        self.emit(&[0xC3]); // ret
+    }
+
+    fn increment_instruction_count(&mut self) {
+        if !self.count_instructions {
+            return;
+        }
+
+        self.emit(&[0x48, 0x8B, 0x43, INSTRUCTION_COUNT]); // mov rax, [rbx + INSTRUCTION_COUNT]
+        self.emit(&[0x48, 0xFF, 0x00]); // inc qword [rax]
+    }
+
+    fn instruction(&mut self, addr: u16, instruction: &Instruction) {
+        // Instructions that fault aren't executed, so `cal` and `ret` are counted after their checks
+        if !matches!(instruction, Instruction::Cal(_) | Instruction::Ret) {
+            self.increment_instruction_count();
+        }
+
+        match *instruction {
+            Instruction::Nop => {},
+            Instruction::Hlt => self.jump_to_halt(),
+            Instruction::Add(a, b, c) => self.alu(a, b, c, 0x00, Carry::Set),
+            Instruction::Sub(a, b, c) => self.alu(a, b, c, 0x28, Carry::Inverted),
+            Instruction::Nor(a, b, c) => {
+                self.load_cl(a);
+                self.load_dl(b);
+                self.emit(&[0x08, 0xD1]); // or cl, dl
+                self.emit(&[0xF6, 0xD1]); // not cl
+                self.emit(&[0x84, 0xC9]); // test cl, cl
+                self.store_cl(c);
+                self.set_flags(Carry::Cleared);
+            },
+            Instruction::And(a, b, c) => self.alu(a, b, c, 0x20, Carry::Cleared),
+            Instruction::Xor(a, b, c) => self.alu(a, b, c, 0x30, Carry::Cleared),
+            Instruction::Rsh(a, c) => {
+                self.load_cl(a);
+                self.emit(&[0xD0, 0xE9]); // shr cl, 1
+                self.store_cl(c);
+            },
+            Instruction::Ldi(a, i) => {
+                self.emit(&[0xB1, i]); // mov cl, i
+                self.store_cl(a);
+            },
+            Instruction::Adi(a, i) => {
+                self.load_cl(a);
+                self.emit(&[0x80, 0xC1, i]); // add cl, i
+                self.store_cl(a);
+                self.set_flags(Carry::Set);
+            },
+            Instruction::Jmp(addr) => {
+                self.emit(&[0xE9]); // jmp addr
+                self.fixup(addr);
+            },
+            Instruction::Brh(ref condition, addr) => {
+                match condition {
+                    Condition::Equal => self.emit(&[0x45, 0x84, 0xFF, 0x0F, 0x85]), // test r15b, r15b; jnz addr
+                    Condition::NotEqual => self.emit(&[0x45, 0x84, 0xFF, 0x0F, 0x84]), // test r15b, r15b; jz addr
+                    Condition::GreaterThanOrEqual => self.emit(&[0x45, 0x84, 0xF6, 0x0F, 0x85]), // test r14b, r14b; jnz addr
+                    Condition::LessThan => self.emit(&[0x45, 0x84, 0xF6, 0x0F, 0x84]), // test r14b, r14b; jz addr
+                }
+                self.fixup(addr);
+            },
+            Instruction::Cal(target) => {
+                if self.check_call_stack {
+                    self.emit(&[0x48, 0x83, 0x7B, CALL_STACK_DEPTH, CALL_STACK_SIZE as u8]); // cmp qword [rbx + CALL_STACK_DEPTH], CALL_STACK_SIZE
+                    self.jump_to_fault(0x83, Fault::status(FaultKind::CallStackOverflow, addr)); // jae fault
+                }
+                self.increment_instruction_count();
+
+                self.emit(&[0x48, 0x8B, 0x4B, CALL_STACK_POINTER]); // mov rcx, [rbx + CALL_STACK_POINTER]
+                self.emit(&[0x48, 0x8D, 0x05]); // lea rax, [addr + 1]
+                self.fixup(addr + 1);
+                self.emit(&[0x48, 0x89, 0x44, 0xCB, CALL_STACK]); // mov [rbx + rcx * 8 + CALL_STACK], rax
+                self.emit(&[0x48, 0xFF, 0xC1]); // inc rcx
+                self.emit(&[0x83, 0xE1, CALL_STACK_SIZE as u8 - 1]); // and ecx, CALL_STACK_SIZE - 1
+                self.emit(&[0x48, 0x89, 0x4B, CALL_STACK_POINTER]); // mov [rbx + CALL_STACK_POINTER], rcx
+                self.emit(&[0x48, 0xFF, 0x43, CALL_STACK_DEPTH]); // inc qword [rbx + CALL_STACK_DEPTH]
+                self.emit(&[0xE9]); // jmp target
+                self.fixup(target);
+            },
+            Instruction::Ret => {
+                if self.check_call_stack {
+                    self.emit(&[0x48, 0x83, 0x7B, CALL_STACK_DEPTH, 0]); // cmp qword [rbx + CALL_STACK_DEPTH], 0
+                    self.jump_to_fault(0x84, Fault::status(FaultKind::CallStackUnderflow, addr)); // je fault
+                }
+                self.increment_instruction_count();
+
+                self.emit(&[0x48, 0x8B, 0x4B, CALL_STACK_POINTER]); // mov rcx, [rbx + CALL_STACK_POINTER]
+                self.emit(&[0x48, 0xFF, 0xC9]); // dec rcx
+                self.emit(&[0x83, 0xE1, CALL_STACK_SIZE as u8 - 1]); // and ecx, CALL_STACK_SIZE - 1
+                self.emit(&[0x48, 0x89, 0x4B, CALL_STACK_POINTER]); // mov [rbx + CALL_STACK_POINTER], rcx
+                self.emit(&[0x48, 0xFF, 0x4B, CALL_STACK_DEPTH]); // dec qword [rbx + CALL_STACK_DEPTH]
+                self.emit(&[0xFF, 0x64, 0xCB, CALL_STACK]); // jmp [rbx + rcx * 8 + CALL_STACK]
+            },
+            Instruction::Lod(a, b, o) => {
+                self.load_address(a, o);
+                self.call_memory_handler(READ_CALLBACK);
+                self.emit(&[0x41, 0x8A, 0x0C, 0x2C]); // mov cl, [r12 + rbp]
+                self.store_cl(b);
+            },
+            Instruction::Str(a, b, o) => {
+                self.load_address(a, o);
+                self.load_cl(b);
+                self.emit(&[0x41, 0x88, 0x0C, 0x2C]); // mov [r12 + rbp], cl
+                self.call_memory_handler(WRITE_CALLBACK);
+            },
+        }
+    }
+
+    /// Emits `cl = a <op> b`, storing the result in `c` and setting the flags
+    fn alu(&mut self, a: u8, b: u8, c: u8, opcode: u8, carry: Carry) {
+        self.load_cl(a);
+        self.load_dl(b);
+        self.emit(&[opcode, 0xD1]); // <op> cl, dl
+        self.store_cl(c);
+        self.set_flags(carry);
+    }
+
+    fn load_cl(&mut self, reg: u8) {
+        self.emit(&[0x41, 0x8A, 0x4D, reg]); // mov cl, [r13 + reg]
+    }
+
+    fn load_dl(&mut self, reg: u8) {
+        self.emit(&[0x41, 0x8A, 0x55, reg]); // mov dl, [r13 + reg]
+    }
+
+    fn store_cl(&mut self, reg: u8) {
+        // Writes to r0 are discarded
+        if reg != 0 {
+            self.emit(&[0x41, 0x88, 0x4D, reg]); // mov [r13 + reg], cl
+        }
+    }
+
+    /// Loads `[reg] + offset` into rbp, which survives the memory handler call
+    fn load_address(&mut self, reg: u8, offset: i8) {
+        self.load_cl(reg);
+        self.emit(&[0x80, 0xC1, offset as u8]); // add cl, offset
+        self.emit(&[0x0F, 0xB6, 0xE9]); // movzx ebp, cl
+    }
+
+    fn call_memory_handler(&mut self, callback: u8) {
+        if cfg!(windows) {
+            self.emit(&[0x4C, 0x89, 0xE1]); // mov rcx, r12
+            self.emit(&[0x89, 0xEA]); // mov edx, ebp
+            self.emit(&[0x4C, 0x8B, 0x43, IO_CONTEXT]); // mov r8, [rbx + IO_CONTEXT]
+        } else {
+            self.emit(&[0x4C, 0x89, 0xE7]); // mov rdi, r12
+            self.emit(&[0x89, 0xEE]); // mov esi, ebp
+            self.emit(&[0x48, 0x8B, 0x53, IO_CONTEXT]); // mov rdx, [rbx + IO_CONTEXT]
+        }
+        self.emit(&[0x48, 0x83, 0xEC, 0x20]); // sub rsp, 32
+        self.emit(&[0xFF, 0x53, callback]); // call [rbx + callback]
+        self.emit(&[0x48, 0x83, 0xC4, 0x20]); // add rsp, 32
+    }
+
+    fn set_flags(&mut self, carry: Carry) {
+        match carry {
+            Carry::Set => self.emit(&[0x41, 0x0F, 0x92, 0xC6]), // setc r14b
+            Carry::Inverted => self.emit(&[0x41, 0x0F, 0x93, 0xC6]), // setnc r14b
+            Carry::Cleared => self.emit(&[0x41, 0xB6, 0x00]), // mov r14b, 0
+        }
+        self.emit(&[0x41, 0x0F, 0x94, 0xC7]); // setz r15b
+    }
+
+    /// Emits a conditional jump (`0F <condition>`) to the epilogue, returning `status`
+    fn jump_to_fault(&mut self, condition: u8, status: u32) {
+        // mov doesn't affect the flags set by the preceding comparison
+        self.emit(&[0xB8]); // mov eax, status
+        self.emit(&status.to_le_bytes());
+        self.emit(&[0x0F, condition]);
+        self.fault_fixups.push(self.code.len());
+        self.emit(&[0; 4]);
+    }
+
+    fn jump_to_halt(&mut self) {
+        self.emit(&[0xE9]); // jmp halt
+        self.halt_fixups.push(self.code.len());
+        self.emit(&[0; 4]);
+    }
+
+    fn fixup(&mut self, addr: u16) {
+        self.fixups.push((self.code.len(), addr));
+        self.emit(&[0; 4]);
+    }
+}
+
+/// How an ALU operation's host carry flag maps onto the BatPU-2 carry flag
+enum Carry {
+    Set,
+    /// x86 sets carry on borrow, the BatPU-2 sets it when there is no borrow
+    Inverted,
+    Cleared,
+}
+
+struct ExecutableBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The buffer is never written to after it has been made executable
+unsafe impl Send for ExecutableBuffer {}
+unsafe impl Sync for ExecutableBuffer {}
+
+#[cfg(unix)]
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> Self {
+        let len = code.len();
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0
+            );
+            if ptr == libc::MAP_FAILED {
+                panic!("Error: Failed to allocate executable memory");
+            }
+
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                panic!("Error: Failed to make memory executable");
+            }
+
+            Self { ptr: ptr as *mut u8, len }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+#[cfg(windows)]
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> Self {
+        use windows_sys::Win32::System::Memory::{VirtualAlloc, VirtualProtect, MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READ, PAGE_READWRITE};
+
+        let len = code.len();
+        unsafe {
+            let ptr = VirtualAlloc(ptr::null(), len, MEM_COMMIT | MEM_RESERVE, PAGE_READWRITE);
+            if ptr.is_null() {
+                panic!("Error: Failed to allocate executable memory");
+            }
+
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+
+            let mut old_protect = 0;
+            if VirtualProtect(ptr, len, PAGE_EXECUTE_READ, &mut old_protect) == 0 {
+                panic!("Error: Failed to make memory executable");
+            }
+
+            Self { ptr: ptr as *mut u8, len }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        use windows_sys::Win32::System::Memory::{VirtualFree, MEM_RELEASE};
+
+        unsafe { VirtualFree(self.ptr as *mut _, 0, MEM_RELEASE) };
+    }
+}
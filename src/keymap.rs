@@ -0,0 +1,139 @@
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::KeyCode;
+
+use batpu_emulator::interface::Button;
+
+/// How long a button stays held after a key press on terminals that don't report key releases.
+/// Long enough to bridge the gap before the terminal's key repeat kicks in.
+const DEFAULT_HOLD: Duration = Duration::from_millis(200);
+
+pub const SCREENSHOT_KEY: KeyCode = KeyCode::F(2);
+pub const SAVE_STATE_KEY: KeyCode = KeyCode::F(5);
+pub const LOAD_STATE_KEY: KeyCode = KeyCode::F(9);
+/// Switches between `--clock-hz` and full speed
+pub const THROTTLE_KEY: KeyCode = KeyCode::Tab;
+/// Runs the program backwards while held
+pub const REWIND_KEY: KeyCode = KeyCode::Backspace;
+
+/// Keys the UI handles before the keymap sees them, so they can't be bound to buttons
+const HOTKEYS: [KeyCode; 6] = [KeyCode::Esc, SCREENSHOT_KEY, SAVE_STATE_KEY, LOAD_STATE_KEY, THROTTLE_KEY, REWIND_KEY];
+
+const DEFAULT_KEYMAP: &str = "\
+up = Up w
+down = Down s
+left = Left a
+right = Right d
+a = z j
+b = x k
+start = Enter
+select = Space
+";
+
+/// Maps terminal keys to controller buttons.
+///
+/// The config file has one `button = key [key...]` binding per line, where keys are either a single character or one
+/// of the named keys (`Up`, `Enter`, `Space`, ...). `hold_ms = <n>` sets how long a press is held on terminals that
+/// only report key presses. Blank lines and lines starting with `#` are ignored.
+pub struct Keymap {
+    bindings: HashMap<KeyCode, Button>,
+    pub hold: Duration,
+}
+
+impl Keymap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path).with_context(|| format!("Failed to read keymap {}", path.display()))?;
+        Self::parse(&src).with_context(|| format!("Invalid keymap {}", path.display()))
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut keymap = Keymap {
+            bindings: HashMap::new(),
+            hold: DEFAULT_HOLD,
+        };
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((name, keys)) = line.split_once('=') else {
+                bail!("line {}: expected `button = key [key...]`", i + 1);
+            };
+
+            let name = name.trim().to_lowercase();
+            if name == "hold_ms" {
+                let ms = keys.trim().parse().with_context(|| format!("line {}: invalid hold time", i + 1))?;
+                keymap.hold = Duration::from_millis(ms);
+                continue;
+            }
+
+            let Some(button) = Button::from_name(&name) else {
+                bail!("line {}: unknown button `{name}`", i + 1);
+            };
+
+            for key in keys.split_whitespace() {
+                let Some(code) = parse_key(key) else {
+                    bail!("line {}: unknown key `{key}`", i + 1);
+                };
+                if HOTKEYS.contains(&code) {
+                    bail!("line {}: `{key}` is a hotkey and can't be bound to a button", i + 1);
+                }
+                keymap.bindings.insert(code, button);
+            }
+        }
+
+        Ok(keymap)
+    }
+
+    pub fn button(&self, key: KeyCode) -> Option<Button> {
+        let key = match key {
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            key => key,
+        };
+        self.bindings.get(&key).copied()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::parse(DEFAULT_KEYMAP).unwrap()
+    }
+}
+
+fn parse_key(key: &str) -> Option<KeyCode> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c.to_ascii_lowercase()));
+    }
+
+    Some(match key.to_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "enter" => KeyCode::Enter,
+        "space" => KeyCode::Char(' '),
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotkeys_are_rejected() {
+        assert_eq!(Keymap::default().button(KeyCode::Char('W')), Some(Button::Up));
+        assert!(Keymap::parse("a = q Enter").is_ok());
+
+        for key in ["Tab", "backspace"] {
+            let err = Keymap::parse(&format!("a = q\nb = {key}")).err().unwrap();
+            assert_eq!(err.to_string(), format!("line 2: `{key}` is a hotkey and can't be bound to a button"));
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! BatPU-2 emulator. [`Emulator`] runs a program one instruction at a time for embedding in other programs, while
+//! [`backend`] runs whole programs natively, through the JIT or on the interpreter like the command line tool.
+
+pub mod assembler;
+pub mod backend;
+pub mod clock;
+pub mod coverage;
+pub mod device;
+pub mod disasm;
+pub mod emulator;
+pub mod gif_recorder;
+pub mod input_script;
+pub mod interface;
+pub mod interpreter;
+pub mod jit;
+pub mod profile;
+pub mod report;
+pub mod rewind;
+pub mod rom;
+pub mod save_state;
+pub mod screenshot;
+pub mod trace;
+pub mod transpiler;
+
+pub use emulator::Emulator;
@@ -1,131 +1,374 @@
-pub mod cli;
-pub mod transpiler;
-pub mod interface;
-pub mod ui;
+use std::{fs, path::Path, sync::{Arc, Mutex}, thread};
 
-use std::{fs, path::PathBuf, process::Command, thread, time::{Duration, Instant}};
-
-use anyhow::Result;
+use batpu_emulator::{
+    assembler,
+    backend::{self, RunStats},
+    clock::Throttle,
+    coverage::{Coverage, SourceMap},
+    disasm,
+    device::TextDevice,
+    gif_recorder::GifRecorder,
+    input_script::{InputRecorder, InputScript},
+    interface::{IoBus, Panel},
+    interpreter::{CallStackPolicy, Interpreter, Observer, ROM_SIZE},
+    profile::Profiler,
+    rom::{self, ProgramFormat},
+    save_state,
+    screenshot,
+    trace::Tracer,
+    transpiler::{self, Target, TranspileOptions},
+    Emulator,
+};
+use anyhow::Context;
 use clap::Parser;
-use cli::Args;
-use ui::ui_main;
+
+use crate::{
+    cli::{Args, Backend, Command},
+    debugger::Debugger,
+    keymap::Keymap,
+    ui::ui_main,
+};
+
+mod cli;
+mod debugger;
+mod keymap;
+mod ui;
 
 fn main() {
     let args = Args::parse();
 
-    let extension = match args.input.extension() {
-        Some(ext) => ext.to_str().unwrap(),
-        None => {
-            println!("Error: Input file must be an assembly or .mc file");
+    if let Some(Command::Disasm { input, format, output }) = &args.command {
+        if let Err(err) = disassemble(input, *format, output.as_deref()) {
+            println!("Error: {err:#}");
+        }
+        return;
+    }
+
+    let Input { program, labels, source } = match load_program(&args) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            println!("Error: {err:#}");
             return;
         }
     };
 
-    fs::create_dir_all("temp").unwrap();
+    let labels = match &args.symbols {
+        Some(path) => match assembler::load_symbols(path) {
+            Ok(symbols) => symbols,
+            Err(err) => {
+                println!("Error: {err:#}");
+                return;
+            }
+        },
+        None => labels,
+    };
 
-    let mut assembled_file = args.input.clone();
+    if let Some(option) = args.interpreter_only_option() {
+        if args.backend.is_some_and(|backend| backend != Backend::Interpreter) {
+            println!("Error: {option} needs the interpreter backend");
+            return;
+        }
+    }
 
-    if extension != "mc" {
-        assemble_file(args.input.to_str().unwrap());
-        assembled_file = PathBuf::from("temp/assembled.mc");
+    let seed = args.seed.unwrap_or_else(|| {
+        let seed = rand::random();
+        println!("RNG seed: {seed}");
+        seed
+    });
+
+    let (io, observers) = match create_io(&args, seed).and_then(|io| Ok((io, create_observers(&args, &program, &labels, source)?))) {
+        Ok(created) => created,
+        Err(err) => {
+            println!("Error: {err:#}");
+            return;
+        }
+    };
+
+    if program.len() > ROM_SIZE {
+        println!("Error: Program is {} words long, but the ROM only holds {ROM_SIZE}", program.len());
+        return;
     }
 
-    let assembly = fs::read_to_string(assembled_file).unwrap();
+    // Trapping into the debugger needs the interpreter, so run the whole program under it
+    if args.debug || args.call_stack_policy == CallStackPolicy::Trap {
+        let instructions = transpiler::disassemble(&program);
+        let mut interpreter = Interpreter::new(instructions, args.execution_options(), io);
+        interpreter.observers = observers;
+        if let Some(path) = &args.load_state {
+            if let Err(err) = save_state::load_file(&mut interpreter, path) {
+                println!("Error: {err:#}");
+                return;
+            }
+        }
 
-    let output = transpiler::transpile(&assembly, false);
-    compile_asm(&output, "compiled").unwrap();
+        let mut debugger = Debugger::new(interpreter, labels, args.rewind_buffer());
 
-    if args.benchmark {
-        let output = transpiler::transpile(&assembly, true);
-        compile_asm(&output, "instruction_count").unwrap();
-        println!("Counting instructions...");
-        let (instruction_count, _) = emulator_main("instruction_count", 1);
-        let instruction_count = instruction_count * args.iterations;
-        println!("Instruction count: {instruction_count}");
-
-        let emulator_thread = thread::spawn(move || {
-            emulator_main("compiled", args.iterations).1
-        });
-
-        if !args.no_gui {
-            ui_main();
+        if !args.debug {
+            debugger.continue_execution();
+            if !debugger.halted() {
+                debugger.run();
+            }
+        } else {
+            debugger.run();
         }
 
-        let time = emulator_thread.join().unwrap();
-        println!("Emulator ran {instruction_count} instructions in {}ms ({}mips)", time.as_millis(), ((instruction_count as u128 / time.as_millis()) / 1000))
+        if let Err(err) = debugger.interpreter.finish_observers() {
+            println!("Error: {err:#}");
+        }
+        finish_run(&args, &mut debugger.interpreter.io);
+        return;
+    }
 
+    // Compiled programs are only paced when they access a device, so a clock rate defaults to the interpreter too
+    let backend = args.backend.unwrap_or_else(|| if backend::nasm_available() && args.interpreter_only_option().is_none() && args.clock_hz.is_none() {
+        Backend::Native
     } else {
-        let emulator_thread = thread::spawn(move || emulator_main("compiled", args.iterations));
-        if !args.no_gui {
-            ui_main();
-        }
-        emulator_thread.join().unwrap();
+        Backend::Interpreter
+    });
+
+    let keymap = match &args.keymap {
+        Some(path) => match Keymap::load(path) {
+            Ok(keymap) => keymap,
+            Err(err) => {
+                println!("Error: {err:#}");
+                return;
+            }
+        },
+        None => Keymap::default(),
+    };
+
+    let (stats, mut io) = match backend {
+        Backend::Native => run_native(program, &args, &keymap, io),
+        Backend::Jit => run_jit(program, &args, &keymap, io),
+        Backend::Interpreter => match create_emulator(&program, &args, io, observers) {
+            Ok(emulator) => run_interpreter(emulator, &args, &keymap),
+            Err(err) => {
+                println!("Error: {err:#}");
+                return;
+            }
+        },
+    };
+
+    finish_run(&args, &mut io);
+
+    if let Some(fault) = stats.fault {
+        println!("Error: {fault}");
     }
 }
 
-fn emulator_main(name: &str, iterations: usize) -> (usize, Duration) {
-    let mut memory: [u8; 256] = [0; 256];
-    let mut registers: [u8; 16] = [0; 16];
-
-    let mut instruction_count = 0;
-
-    let execution_time;
-    unsafe {
-        let lib = libloading::Library::new(format!("temp/{name}.dll")).unwrap();
-        let main: libloading::Symbol<CompiledMain> = lib.get(b"_main").unwrap();
-        // println!("Running");
-        let mem_ptr = memory.as_mut_ptr();
-        let reg_ptr = registers.as_mut_ptr();
-        // println!("{:?}", mem_ptr);
-        let start_time = Instant::now();
-        #[allow(unused_assignments)]
-        for _ in 0..iterations {
-            memory = [0; 256];
-            registers = [0; 16];
-            main(mem_ptr, reg_ptr, interface::on_mem_read, interface::on_mem_write, &mut instruction_count as *mut usize);
-        }
-        execution_time = start_time.elapsed();
+/// The input file, assembled or loaded
+struct Input {
+    program: Vec<u16>,
+    labels: Vec<(String, u16)>,
+    /// The source, if the input is a text file
+    source: Option<SourceMap>,
+}
+
+fn load_program(args: &Args) -> anyhow::Result<Input> {
+    let input = args.input();
+    let data = fs::read(input).with_context(|| format!("Failed to read {}", input.display()))?;
+    let format = args.format.unwrap_or_else(|| rom::detect_format(input, &data));
+
+    if format == ProgramFormat::Assembly {
+        let src = String::from_utf8(data).with_context(|| format!("{} is not a text file", input.display()))?;
+        let assembly = assembler::assemble(&src, input)?;
+        let source = SourceMap { path: input.to_path_buf(), src, lines: assembly.lines };
+        return Ok(Input { program: assembly.program, labels: assembly.labels, source: Some(source) });
     }
-    // println!("{:?}", registers)
-    (instruction_count, execution_time)
+
+    let rom = rom::parse(&data, format).with_context(|| format!("Invalid program {}", input.display()))?;
+    // The text formats have already been checked to be UTF-8
+    let source = rom.lines.map(|lines| SourceMap { path: input.to_path_buf(), src: String::from_utf8_lossy(&data).into_owned(), lines });
+    Ok(Input { program: rom.program, labels: Vec::new(), source })
 }
 
-fn assemble_file(file: &str) {
-    if !Command::new("python")
-        .arg("assembler/main.py")
-        .arg(file)
-        .arg("temp/assembled.mc")
-        .status().expect("Python failed to run")
-        .success() {
-        panic!("Error: Failed to assemble file");
+fn disassemble(input: &Path, format: Option<ProgramFormat>, output: Option<&Path>) -> anyhow::Result<()> {
+    let listing = disasm::listing(&rom::load(input, format)?.program);
+
+    match output {
+        Some(path) => fs::write(path, listing).with_context(|| format!("Failed to write {}", path.display())),
+        None => {
+            print!("{listing}");
+            Ok(())
+        },
+    }
+}
+
+fn create_io(args: &Args, seed: u64) -> anyhow::Result<IoBus> {
+    let mut io = IoBus::new(seed);
+    io.attach(247..=249, TextDevice::new(args.character_encoding));
+    io.state.throttle = args.clock_hz.map(Throttle::new);
+
+    if let Some(path) = &args.input_script {
+        io.state.input_script = Some(InputScript::load(path)?);
+    }
+    if let Some(path) = &args.record_input {
+        io.state.input_recorder = Some(InputRecorder::create(path)?);
+    }
+    if let Some(path) = &args.record {
+        io.state.gif_recorder = Some(GifRecorder::create(path, args.screenshot_options(), args.record_clock_hz)?);
+    }
+
+    Ok(io)
+}
+
+fn finish_run(args: &Args, io: &mut IoBus) {
+    // Dropping the recorder flushes it
+    io.state.input_recorder.take();
+
+    if let Some(recorder) = io.state.gif_recorder.take() {
+        if let Err(err) = recorder.finish() {
+            println!("Error: {err:#}");
+        }
+    }
+
+    if let Some(path) = &args.screenshot_on_halt {
+        if let Err(err) = screenshot::save(path, &io.state.panel.lock().unwrap().screen, &args.screenshot_options()) {
+            println!("Error: {err:#}");
+        }
     }
 }
 
-fn compile_asm(src: &str, name: &str) -> Result<()> {
-    fs::write(format!("temp/{name}.asm"), src).unwrap();
-
-    if !Command::new("nasm")
-        .arg("-f")
-        .arg("win64")
-        .arg(format!("temp/{name}.asm"))
-        .arg("-O0")
-        .arg("-o")
-        .arg(format!("temp/{name}.obj"))
-        .status().expect("Nasm failed to run")
-        .success() {
-            panic!("Error: Failed to compile assembly");
+fn run_native(program: Vec<u16>, args: &Args, keymap: &Keymap, mut io: IoBus) -> (RunStats, IoBus) {
+    let mut options = TranspileOptions {
+        target: args.target.unwrap_or_else(Target::host),
+        count_instructions: args.needs_instruction_count(),
+        execution: args.execution_options(),
+    };
+
+    fs::create_dir_all("temp").unwrap();
+
+    // Named after the process so emulators running side by side don't overwrite each other's builds
+    let name = format!("compiled_{}", std::process::id());
+    let count_name = format!("instruction_count_{}", std::process::id());
+
+    let output = transpiler::transpile(&program, &options);
+    backend::compile_asm(&output, &name, options.target).unwrap();
+
+    let count_separately = args.benchmark && !options.count_instructions;
+    if count_separately {
+        options.count_instructions = true;
+        let output = transpiler::transpile(&program, &options);
+        backend::compile_asm(&output, &count_name, options.target).unwrap();
+    }
+
+    let iterations = args.iterations;
+    let instructions = transpiler::disassemble(&program);
+    let seed = io.seed();
+
+    let run_name = name.clone();
+    let (mut stats, io) = run_with_ui(args, keymap, io.state.panel.clone(), None, move || {
+        let stats = backend::emulator_main(&run_name, &instructions, iterations, options.target, &mut io);
+        (stats, io)
+    });
+    backend::remove_build(&name, options.target);
+
+    if args.benchmark {
+        // Input scripts can't be replayed, so reuse the count from the run itself when it has one
+        if count_separately {
+            println!("Counting instructions...");
+            let instructions = transpiler::disassemble(&program);
+            stats.instruction_count = backend::emulator_main(&count_name, &instructions, 1, options.target, &mut IoBus::new(seed)).instruction_count * iterations;
+            backend::remove_build(&count_name, options.target);
         }
+        println!("Instruction count: {}", stats.instruction_count);
+        print_benchmark(&stats);
+    }
+
+    (stats, io)
+}
 
-    if !Command::new("golink")
-        .args(["/dll", "/entry", "_DllMain", format!("temp/{name}.obj").as_str()])
-        .status().expect("Golink failed to run")
-        .success() {
-            panic!("Error: Failed to link assembly");
+fn run_jit(program: Vec<u16>, args: &Args, keymap: &Keymap, mut io: IoBus) -> (RunStats, IoBus) {
+    let iterations = args.iterations;
+    let options = args.execution_options();
+    let count_instructions = args.needs_instruction_count();
+    let instructions = transpiler::disassemble(&program);
+    let seed = io.seed();
+
+    let (mut stats, io) = run_with_ui(args, keymap, io.state.panel.clone(), None, move || {
+        let stats = backend::jit_main(&instructions, count_instructions, options, iterations, &mut io);
+        (stats, io)
+    });
+
+    if args.benchmark {
+        // Input scripts can't be replayed, so reuse the count from the run itself when it has one
+        if !count_instructions {
+            println!("Counting instructions...");
+            let instructions = transpiler::disassemble(&program);
+            stats.instruction_count = backend::jit_main(&instructions, true, options, 1, &mut IoBus::new(seed)).instruction_count * iterations;
         }
+        println!("Instruction count: {}", stats.instruction_count);
+        print_benchmark(&stats);
+    }
+
+    (stats, io)
+}
+
+/// Observers for the interpreter-only options that watch every instruction
+fn create_observers(args: &Args, program: &[u16], labels: &[(String, u16)], source: Option<SourceMap>) -> anyhow::Result<Vec<Box<dyn Observer>>> {
+    let mut observers: Vec<Box<dyn Observer>> = Vec::new();
+    if let Some(path) = &args.trace {
+        observers.push(Box::new(Tracer::create(path, args.trace_format, args.trace_filter())?));
+    }
+    if let Some(path) = &args.coverage {
+        observers.push(Box::new(Coverage::new(path, &transpiler::disassemble(program), labels, source)));
+    }
+    if let Some(path) = &args.profile {
+        observers.push(Box::new(Profiler::new(path, &transpiler::disassemble(program), labels)));
+    }
+    Ok(observers)
+}
 
-    Ok(())
+fn create_emulator(program: &[u16], args: &Args, io: IoBus, observers: Vec<Box<dyn Observer>>) -> anyhow::Result<Emulator> {
+    let mut emulator = Emulator::with_io(program, args.execution_options(), io)?;
+    for observer in observers {
+        emulator.add_observer(observer);
+    }
+    if let Some(buffer) = args.rewind_buffer() {
+        emulator.enable_rewind(buffer);
+    }
+    if let Some(path) = &args.load_state {
+        emulator.load_state(path)?;
+    }
+    Ok(emulator)
 }
 
-type MemoryHandler = unsafe extern "C" fn(mem_space: *mut u8, addr: usize);
+fn run_interpreter(mut emulator: Emulator, args: &Args, keymap: &Keymap) -> (RunStats, IoBus) {
+    let iterations = args.iterations;
+    let panel = emulator.io().state.panel.clone();
+    let state_file = args.state_file();
+    let (stats, mut emulator) = run_with_ui(args, keymap, panel, Some(&state_file), move || {
+        let stats = backend::interpreter_main(&mut emulator, iterations);
+        (stats, emulator)
+    });
+
+    // Observers print their summaries, so wait until the UI is gone
+    if let Err(err) = emulator.finish_observers() {
+        println!("Error: {err:#}");
+    }
+
+    if args.benchmark {
+        println!("Instruction count: {}", stats.instruction_count);
+        print_benchmark(&stats);
+    }
+
+    (stats, emulator.into_io())
+}
+
+/// Runs the emulator on its own thread while the UI shows its panel.
+/// `state_file` is where the save state hotkeys save to, if the backend supports them.
+fn run_with_ui<T: Send + 'static>(args: &Args, keymap: &Keymap, panel: Arc<Mutex<Panel>>, state_file: Option<&Path>, emulate: impl FnOnce() -> T + Send + 'static) -> T {
+    let emulator_thread = thread::spawn(emulate);
+
+    if !args.no_gui {
+        ui_main(&panel, keymap, args.input_script.is_none(), &args.screenshot_options(), state_file);
+    }
+
+    emulator_thread.join().unwrap()
+}
+
+fn print_benchmark(stats: &RunStats) {
+    let mips = stats.instruction_count as f64 / stats.time.as_secs_f64() / 1_000_000.0;
+    println!("Emulator ran {} instructions in {}ms ({mips:.0}mips)", stats.instruction_count, stats.time.as_millis())
+}
 
-type CompiledMain = unsafe extern "C" fn(mem_space: *mut u8, registers: *mut u8, on_mem_read: MemoryHandler, on_mem_write: MemoryHandler, instruction_count: *mut usize);
\ No newline at end of file
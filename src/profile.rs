@@ -0,0 +1,228 @@
+use std::{collections::HashMap, fmt::Write as _, fs, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    interpreter::{CpuState, Interpreter, Observer, CALL_STACK_SIZE},
+    report::{self, percent},
+    transpiler::Instruction,
+};
+
+/// Hotspots listed in the report and summary
+const HOTSPOTS: usize = 20;
+
+/// A call stack seen during the run: the routine it entered and the stack it was called from
+struct Frame {
+    parent: usize,
+    routine: u16,
+    depth: usize,
+    /// Instructions executed directly in this routine with this stack
+    count: u64,
+}
+
+/// Counts how often every instruction of the program runs and which routines they ran in.
+///
+/// Routines are entered by `cal` and left by `ret`, with everything outside of them in the routine at address 0.
+/// Once the run is over it writes to its output directory:
+/// - `profile.txt`, the hottest addresses, the inclusive and exclusive counts of each routine, the totals of each
+///   labelled block and the execution count of every address
+/// - `profile.folded`, the counts per call stack in the folded format flamegraph tools read
+///
+/// and prints the hotspots and routines.
+pub struct Profiler {
+    output: PathBuf,
+    instructions: Vec<Instruction>,
+    /// Label names and addresses, sorted by address
+    labels: Vec<(String, u16)>,
+    counts: Vec<u64>,
+    /// Times each routine was called
+    calls: HashMap<u16, u64>,
+    /// Every call stack seen, with the outermost one first
+    frames: Vec<Frame>,
+    children: HashMap<(usize, u16), usize>,
+    /// Index of the current call stack in `frames`
+    frame: usize,
+}
+
+impl Profiler {
+    /// Generates labels from the jump and call targets if `labels` is empty
+    pub fn new(output: &Path, instructions: &[Instruction], labels: &[(String, u16)]) -> Self {
+        Self {
+            output: output.to_path_buf(),
+            instructions: instructions.to_vec(),
+            labels: report::sorted_labels(instructions, labels),
+            counts: vec![0; instructions.len()],
+            calls: HashMap::new(),
+            frames: vec![Frame { parent: 0, routine: 0, depth: 0, count: 0 }],
+            children: HashMap::new(),
+            frame: 0,
+        }
+    }
+
+    /// The label at `addr`, or the nearest one before it with an offset
+    fn name(&self, addr: u16) -> String {
+        match self.labels.iter().rev().find(|&&(_, label)| label <= addr) {
+            Some((name, label)) if *label == addr => name.clone(),
+            Some((name, label)) => format!("{name}+{}", addr - label),
+            None if addr == 0 => "(start)".to_string(),
+            None => format!("(start)+{addr}"),
+        }
+    }
+
+    /// Frame indices from the outermost routine to `frame`
+    fn path(&self, mut frame: usize) -> Vec<usize> {
+        let mut path = vec![frame];
+        while frame != 0 {
+            frame = self.frames[frame].parent;
+            path.push(frame);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Name, calls, inclusive and exclusive count of every routine that ran, hottest first
+    fn routines(&self) -> Vec<(String, u64, u64, u64)> {
+        let mut totals = HashMap::<u16, (u64, u64)>::new();
+        for (i, frame) in self.frames.iter().enumerate() {
+            totals.entry(frame.routine).or_default().1 += frame.count;
+
+            // Recursive calls only count once towards the inclusive count
+            let mut routines = self.path(i).into_iter().map(|frame| self.frames[frame].routine).collect::<Vec<_>>();
+            routines.sort();
+            routines.dedup();
+            for routine in routines {
+                totals.entry(routine).or_default().0 += frame.count;
+            }
+        }
+
+        let mut routines = totals.into_iter()
+            .filter(|&(_, (inclusive, _))| inclusive > 0)
+            .map(|(routine, (inclusive, exclusive))| (self.name(routine), self.calls.get(&routine).copied().unwrap_or(0), inclusive, exclusive))
+            .collect::<Vec<_>>();
+        routines.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        routines
+    }
+
+    /// The labelled blocks with their total counts, like the coverage report's blocks, hottest first
+    fn blocks(&self) -> Vec<(String, u64)> {
+        let mut totals = HashMap::<String, u64>::new();
+        for (addr, &count) in self.counts.iter().enumerate() {
+            let name = match self.labels.iter().rev().find(|&&(_, label)| label as usize <= addr) {
+                Some((name, _)) => name.clone(),
+                None => "(start)".to_string(),
+            };
+            *totals.entry(name).or_default() += count;
+        }
+
+        let mut blocks = totals.into_iter().filter(|&(_, count)| count > 0).collect::<Vec<_>>();
+        blocks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        blocks
+    }
+
+    fn hotspots(&self) -> String {
+        let total = self.counts.iter().sum::<u64>();
+        let mut addresses = (0..self.counts.len()).filter(|&addr| self.counts[addr] > 0).collect::<Vec<_>>();
+        addresses.sort_by_key(|&addr| std::cmp::Reverse(self.counts[addr]));
+
+        let mut report = String::from("addr       count       %  location                  instruction\n");
+        for &addr in addresses.iter().take(HOTSPOTS) {
+            writeln!(
+                report, "{addr:04} {:>11} {:>7}  {:<24}  {:?}",
+                self.counts[addr], percent(self.counts[addr], total), self.name(addr as u16), self.instructions[addr],
+            ).unwrap();
+        }
+        report
+    }
+
+    fn routine_report(&self) -> String {
+        let total = self.counts.iter().sum::<u64>();
+        let routines = self.routines();
+        let width = routines.iter().map(|(name, ..)| name.len()).max().unwrap_or(0).max(7);
+
+        let mut report = format!("{:<width$}  {:>9}  {:>11}  {:>7}  {:>11}  {:>7}\n", "routine", "calls", "inclusive", "%", "exclusive", "%");
+        for (name, calls, inclusive, exclusive) in routines {
+            writeln!(
+                report, "{name:<width$}  {calls:>9}  {inclusive:>11}  {:>7}  {exclusive:>11}  {:>7}",
+                percent(inclusive, total), percent(exclusive, total),
+            ).unwrap();
+        }
+        report
+    }
+
+    fn report(&self) -> String {
+        let total = self.counts.iter().sum::<u64>();
+        let mut report = format!("Instructions executed: {total}\n\nHotspots:\n{}\nRoutines:\n{}\nBlocks:\n", self.hotspots(), self.routine_report());
+
+        for (name, count) in self.blocks() {
+            writeln!(report, "{name:<24} {count:>11} {:>7}", percent(count, total)).unwrap();
+        }
+
+        report.push_str("\nAddresses:\n");
+        let mut labels = self.labels.iter().peekable();
+        for (addr, instruction) in self.instructions.iter().enumerate() {
+            while let Some((name, _)) = labels.next_if(|&(_, label)| *label as usize <= addr) {
+                writeln!(report, "{name}:").unwrap();
+            }
+            writeln!(report, "{addr:04} {:>11}  {instruction:?}", self.counts[addr]).unwrap();
+        }
+
+        report
+    }
+
+    fn folded(&self) -> String {
+        let mut folded = String::new();
+        for (i, frame) in self.frames.iter().enumerate().filter(|(_, frame)| frame.count > 0) {
+            let stack = self.path(i).into_iter().map(|frame| self.name(self.frames[frame].routine)).collect::<Vec<_>>();
+            writeln!(folded, "{} {}", stack.join(";"), frame.count).unwrap();
+        }
+        folded
+    }
+}
+
+impl Observer for Profiler {
+    fn observe(&mut self, before: &CpuState, interpreter: &Interpreter) {
+        let addr = before.pc as usize;
+        // The nop padding after the program isn't part of it
+        if addr >= self.counts.len() {
+            return;
+        }
+
+        self.counts[addr] += 1;
+        self.frames[self.frame].count += 1;
+
+        match before.instruction {
+            // Deeper calls than the hardware stack holds only wrap around, so they stay in the caller
+            Instruction::Cal(routine) if self.frames[self.frame].depth < CALL_STACK_SIZE => {
+                *self.calls.entry(routine).or_default() += 1;
+                let next = self.frames.len();
+                let depth = self.frames[self.frame].depth + 1;
+                let parent = self.frame;
+                self.frame = *self.children.entry((parent, routine)).or_insert(next);
+                if self.frame == next {
+                    self.frames.push(Frame { parent, routine, depth, count: 0 });
+                }
+            },
+            Instruction::Ret => self.frame = self.frames[self.frame].parent,
+            _ => {},
+        }
+
+        // Resets and loaded states empty the stack without a `ret`
+        if interpreter.call_stack.is_empty() {
+            self.frame = 0;
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.output).with_context(|| format!("Failed to create {}", self.output.display()))?;
+
+        for (name, contents) in [("profile.txt", self.report()), ("profile.folded", self.folded())] {
+            let path = self.output.join(name);
+            fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+
+        println!("Profile: {} instructions executed", self.counts.iter().sum::<u64>());
+        println!("Hotspots:\n{}", self.hotspots().trim_end());
+        println!("Routines:\n{}", self.routine_report().trim_end());
+        Ok(())
+    }
+}
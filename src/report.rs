@@ -0,0 +1,22 @@
+use crate::transpiler::{self, Instruction};
+
+/// `labels` sorted by address with one per address, or the [`generated_labels`](transpiler::generated_labels) if
+/// there are none
+pub fn sorted_labels(instructions: &[Instruction], labels: &[(String, u16)]) -> Vec<(String, u16)> {
+    let mut labels = if labels.is_empty() {
+        transpiler::generated_labels(instructions)
+    } else {
+        labels.to_vec()
+    };
+    labels.sort_by_key(|&(_, addr)| addr);
+    labels.dedup_by_key(|(_, addr)| *addr);
+    labels
+}
+
+/// `part` as a percentage of `total` with one decimal, or `-` if `total` is 0
+pub fn percent(part: u64, total: u64) -> String {
+    if total == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", part as f64 * 100.0 / total as f64)
+}
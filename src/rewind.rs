@@ -0,0 +1,238 @@
+use std::{collections::VecDeque, mem};
+
+use crate::{
+    input_script::{EventTime, InputScript},
+    interpreter::Interpreter,
+    save_state::{StateReader, StateWriter},
+};
+
+/// The recent history of an interpreter, so it can be stepped backwards.
+///
+/// A checkpoint of the machine state is taken every `interval` instructions. Only the newest one is kept in full;
+/// older ones are stored as the bytes that differ from the checkpoint after them. Together with a log of the controller
+/// input the program read, any point since the oldest checkpoint is reached by restoring the checkpoint before it and
+/// replaying the decoded instructions from there. The oldest checkpoints are dropped once the history takes up more
+/// than `memory_limit` bytes.
+pub struct RewindBuffer {
+    interval: usize,
+    memory_limit: usize,
+    /// Instruction count and full state of the newest checkpoint
+    latest: Option<(usize, Vec<u8>)>,
+    /// Older checkpoints, oldest first, each stored as a delta from the checkpoint after it
+    history: VecDeque<(usize, Vec<u8>)>,
+    /// Bytes used by the deltas in `history`
+    history_size: usize,
+    /// Instruction count at which the next checkpoint is due
+    next_checkpoint: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: usize, memory_limit: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            memory_limit,
+            latest: None,
+            history: VecDeque::new(),
+            history_size: 0,
+            next_checkpoint: 0,
+        }
+    }
+
+    /// Forgets the history, for when the machine is reset or another state is loaded
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.history.clear();
+        self.history_size = 0;
+        self.next_checkpoint = 0;
+    }
+
+    /// Instruction count of the oldest point that can be rewound to
+    pub fn oldest(&self) -> Option<usize> {
+        self.history.front().or(self.latest.as_ref()).map(|&(count, _)| count)
+    }
+
+    /// Takes a checkpoint if `interval` instructions have passed since the last one. Call it before every step.
+    #[inline]
+    pub fn record(&mut self, interpreter: &mut Interpreter) {
+        if interpreter.instruction_count >= self.next_checkpoint {
+            self.checkpoint(interpreter);
+        }
+    }
+
+    fn checkpoint(&mut self, interpreter: &mut Interpreter) {
+        let count = interpreter.instruction_count;
+        if self.latest.is_none() {
+            interpreter.io.state.input_log = Some(Vec::new());
+        }
+        self.next_checkpoint = count + self.interval;
+
+        let mut state = StateWriter::default();
+        interpreter.save_state(&mut state);
+
+        if let Some((previous_count, previous)) = self.latest.replace((count, state.into_bytes())) {
+            let delta = diff(&self.latest.as_ref().unwrap().1, &previous);
+            self.history_size += delta.len();
+            self.history.push_back((previous_count, delta));
+        }
+
+        self.evict(interpreter);
+    }
+
+    /// Returns the machine to how it was after `target` instructions, or as far back as the history goes.
+    /// Checkpoints after that point are dropped, since running on from there can take a different path.
+    /// Returns the instruction count that was reached.
+    pub fn rewind_to(&mut self, interpreter: &mut Interpreter, target: usize) -> usize {
+        if target < interpreter.instruction_count {
+            self.go_to(interpreter, target);
+        }
+        interpreter.instruction_count
+    }
+
+    /// Rewinds to the most recent earlier point where `stop` returns true, checked before each instruction.
+    /// If there is none, rewinds as far as the history goes and returns false.
+    pub fn rewind_until(&mut self, interpreter: &mut Interpreter, mut stop: impl FnMut(&Interpreter) -> bool) -> bool {
+        let end = interpreter.instruction_count;
+        let Some((latest_count, latest)) = &self.latest else {
+            return false;
+        };
+
+        // Search one checkpoint at a time, newest first, so only the stretch before `segment_end` is replayed
+        let mut checkpoints = self.history.iter().rev();
+        let (mut count, mut state) = (*latest_count, latest.clone());
+        let mut segment_end = end;
+        let found = loop {
+            let mut found = None;
+            if count < segment_end {
+                replay(interpreter, &state, segment_end, |interpreter| if stop(interpreter) {
+                    found = Some(interpreter.instruction_count);
+                });
+            }
+            if found.is_some() {
+                break found;
+            }
+
+            let Some((previous_count, delta)) = checkpoints.next() else {
+                break None;
+            };
+            patch(&mut state, delta);
+            segment_end = count;
+            count = *previous_count;
+        };
+
+        self.go_to(interpreter, found.unwrap_or(0));
+        found.is_some()
+    }
+
+    /// Restores the newest checkpoint at or before `target` and replays up to it, dropping the checkpoints after it
+    fn go_to(&mut self, interpreter: &mut Interpreter, target: usize) {
+        let Some((mut count, mut state)) = self.latest.take() else {
+            return;
+        };
+
+        while count > target {
+            let Some((previous_count, delta)) = self.history.pop_back() else {
+                break;
+            };
+            patch(&mut state, &delta);
+            self.history_size -= delta.len();
+            count = previous_count;
+        }
+
+        replay(interpreter, &state, target, |_| {});
+        self.latest = Some((count, state));
+        self.next_checkpoint = count + self.interval;
+
+        let reached = interpreter.instruction_count;
+        if let Some(log) = &mut interpreter.io.state.input_log {
+            log.retain(|&(time, _)| time <= reached);
+        }
+    }
+
+    /// Drops the oldest checkpoints until the history fits in the memory limit, keeping at least the newest one
+    fn evict(&mut self, interpreter: &mut Interpreter) {
+        let log = interpreter.io.state.input_log.get_or_insert_with(Vec::new);
+        let latest_size = self.latest.as_ref().map_or(0, |(_, state)| state.len());
+
+        while latest_size + self.history_size + log.len() * mem::size_of::<(usize, u8)>() > self.memory_limit {
+            let Some((_, delta)) = self.history.pop_front() else {
+                break;
+            };
+            self.history_size -= delta.len();
+
+            // Replaying from the oldest checkpoint only needs the last input read before it
+            let oldest = self.oldest().unwrap();
+            if let Some(first) = log.iter().rposition(|&(time, _)| time <= oldest) {
+                log.drain(..first);
+            }
+        }
+    }
+}
+
+/// Restores `state` and runs the interpreter up to `target` instructions, feeding the program the logged input.
+/// `visit` sees the machine before every instruction.
+fn replay(interpreter: &mut Interpreter, state: &[u8], target: usize, mut visit: impl FnMut(&Interpreter)) {
+    interpreter.load_state(&mut StateReader::new(state)).unwrap();
+
+    // Keep replayed input and screens out of the log, any recordings and the observers, and replay at full speed
+    let io = &mut interpreter.io.state;
+    let log = io.input_log.take().unwrap_or_default();
+    let events = log.iter().map(|&(time, value)| (EventTime::Instruction(time), value)).collect();
+    let input_script = io.input_script.replace(InputScript::from_events(events));
+    let input_recorder = io.input_recorder.take();
+    let gif_recorder = io.gif_recorder.take();
+    let throttle = io.throttle.take();
+    let observers = mem::take(&mut interpreter.observers);
+
+    while interpreter.instruction_count < target {
+        visit(interpreter);
+        if !interpreter.step() {
+            break;
+        }
+    }
+
+    let io = &mut interpreter.io.state;
+    io.input_log = Some(log);
+    io.input_script = input_script;
+    io.input_recorder = input_recorder;
+    io.gif_recorder = gif_recorder;
+    io.throttle = throttle;
+    interpreter.observers = observers;
+}
+
+/// The runs of bytes that turn `from` into `to`, preceded by the length of `to`
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = StateWriter::default();
+    delta.u32(to.len() as u32);
+
+    let differs = |i: usize| from.get(i) != Some(&to[i]);
+    let mut start = 0;
+    while start < to.len() {
+        if !differs(start) {
+            start += 1;
+            continue;
+        }
+
+        let mut end = start + 1;
+        while end < to.len() && end - start < u16::MAX as usize && differs(end) {
+            end += 1;
+        }
+
+        delta.u32(start as u32);
+        delta.u16((end - start) as u16);
+        delta.bytes(&to[start..end]);
+        start = end;
+    }
+
+    delta.into_bytes()
+}
+
+fn patch(state: &mut Vec<u8>, delta: &[u8]) {
+    let mut delta = StateReader::new(delta);
+    state.resize(delta.u32().unwrap() as usize, 0);
+
+    while let Ok(start) = delta.u32() {
+        let len = delta.u16().unwrap() as usize;
+        let start = start as usize;
+        state[start..start + len].copy_from_slice(delta.bytes(len).unwrap());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::ValueEnum;
+
+use crate::interpreter::ROM_SIZE;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgramFormat {
+    /// Source for the built-in assembler
+    Assembly,
+    /// One 16 digit binary word per line
+    Mc,
+    /// One hex word per line
+    Hex,
+    /// Intel HEX records, read as big-endian words
+    Ihex,
+    /// Raw ROM image with big-endian words
+    BinBe,
+    /// Raw ROM image with little-endian words
+    BinLe
+}
+
+/// Machine code loaded from a file
+pub struct Rom {
+    pub program: Vec<u16>,
+    /// The (1-based) line of each word, for the text formats
+    pub lines: Option<Vec<usize>>,
+}
+
+/// The format of a file with the given extension and contents, with anything unknown taken to be assembly.
+/// `.hex` files are Intel HEX if they start with a record and hex words otherwise.
+pub fn detect_format(path: &Path, data: &[u8]) -> ProgramFormat {
+    match path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+        Some("mc") => ProgramFormat::Mc,
+        Some("ihex" | "ihx") => ProgramFormat::Ihex,
+        Some("hex") => {
+            let text = String::from_utf8_lossy(data);
+            match text.lines().map(strip_comment).find(|line| !line.is_empty()) {
+                Some(line) if line.starts_with(':') => ProgramFormat::Ihex,
+                _ => ProgramFormat::Hex,
+            }
+        },
+        Some("bin" | "rom") => ProgramFormat::BinBe,
+        _ => ProgramFormat::Assembly,
+    }
+}
+
+/// Loads machine code in any format but assembly, detecting it from the file if `format` is `None`
+pub fn load(path: &Path, format: Option<ProgramFormat>) -> Result<Rom> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let format = format.unwrap_or_else(|| detect_format(path, &data));
+    parse(&data, format).with_context(|| format!("Invalid program {}", path.display()))
+}
+
+/// Parses machine code in any format but assembly
+pub fn parse(data: &[u8], format: ProgramFormat) -> Result<Rom> {
+    let text = || std::str::from_utf8(data).context("not a text file");
+    let raw = |program| Rom { program, lines: None };
+
+    match format {
+        ProgramFormat::Assembly => bail!("assembly isn't machine code"),
+        ProgramFormat::Mc => parse_mc(text()?),
+        ProgramFormat::Hex => parse_hex(text()?),
+        ProgramFormat::Ihex => parse_intel_hex(text()?).map(raw),
+        ProgramFormat::BinBe => parse_binary(data, u16::from_be_bytes).map(raw),
+        ProgramFormat::BinLe => parse_binary(data, u16::from_le_bytes).map(raw),
+    }
+}
+
+/// Parses a `.mc` file: one 16 digit binary word per line.
+/// Blank lines and comments starting with `#`, `//` or `;` are skipped.
+pub fn parse_mc(src: &str) -> Result<Rom> {
+    parse_words(src, |word| {
+        (word.len() == 16 && word.chars().all(|c| c == '0' || c == '1')).then(|| u16::from_str_radix(word, 2).unwrap())
+    }, "a 16 digit binary word")
+}
+
+/// Parses one hex word per line, optionally prefixed with `0x`, skipping blank lines and comments like [`parse_mc`]
+pub fn parse_hex(src: &str) -> Result<Rom> {
+    parse_words(src, |word| {
+        let digits = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).unwrap_or(word);
+        (!digits.is_empty() && digits.len() <= 4).then(|| u16::from_str_radix(digits, 16).ok()).flatten()
+    }, "a hex word of up to 4 digits")
+}
+
+fn parse_words(src: &str, parse: impl Fn(&str) -> Option<u16>, expected: &str) -> Result<Rom> {
+    let mut program = Vec::new();
+    let mut lines = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let word = strip_comment(line);
+        if word.is_empty() {
+            continue;
+        }
+
+        let Some(value) = parse(word) else {
+            bail!("line {}: expected {expected}, found `{word}`", i + 1);
+        };
+        program.push(value);
+        lines.push(i + 1);
+    }
+
+    Ok(Rom { program, lines: Some(lines) })
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(['#', ';']).next().unwrap().split("//").next().unwrap().trim()
+}
+
+/// Parses Intel HEX records into a byte image, read as big-endian words like the `.mc` digits.
+/// Gaps between records are filled with zeroes, which decode to `nop`.
+pub fn parse_intel_hex(src: &str) -> Result<Vec<u16>> {
+    let mut image = Vec::new();
+    let mut base = 0;
+
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = parse_record(line).with_context(|| format!("line {}", i + 1))?;
+        let (kind, addr, data) = (record[3], u16::from_be_bytes([record[1], record[2]]) as usize, &record[4..record.len() - 1]);
+        match kind {
+            // Data
+            0x00 => {
+                let start = base + addr;
+                ensure!(start + data.len() <= ROM_SIZE * 2, "line {}: data at byte {start} is past the end of the {ROM_SIZE} word ROM", i + 1);
+                if image.len() < start + data.len() {
+                    image.resize(start + data.len(), 0);
+                }
+                image[start..start + data.len()].copy_from_slice(data);
+            },
+            // End of file
+            0x01 => break,
+            // Extended segment and linear addresses
+            0x02 | 0x04 => {
+                ensure!(data.len() == 2, "line {}: address records hold 2 bytes", i + 1);
+                let value = u16::from_be_bytes([data[0], data[1]]) as usize;
+                base = if kind == 0x02 { value << 4 } else { value << 16 };
+            },
+            // Start addresses don't apply to the BatPU-2, which always starts at 0
+            0x03 | 0x05 => {},
+            _ => bail!("line {}: unknown record type {kind:02X}", i + 1),
+        }
+    }
+
+    parse_binary(&image, u16::from_be_bytes)
+}
+
+/// The bytes of an Intel HEX record: length, address, type, data and checksum
+fn parse_record(line: &str) -> Result<Vec<u8>> {
+    let Some(digits) = line.strip_prefix(':') else {
+        bail!("records start with `:`");
+    };
+    ensure!(digits.len().is_multiple_of(2) && digits.is_ascii(), "`{line}` is not a record");
+
+    let bytes = (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).with_context(|| format!("`{line}` is not a record")))
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(bytes.len() >= 5 && bytes.len() == bytes[0] as usize + 5, "record length doesn't match its data");
+    ensure!(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0, "checksum mismatch");
+    Ok(bytes)
+}
+
+/// Splits a raw ROM image into words
+pub fn parse_binary(data: &[u8], word: fn([u8; 2]) -> u16) -> Result<Vec<u16>> {
+    ensure!(data.len().is_multiple_of(2), "the image is {} bytes long, which isn't a whole number of words", data.len());
+    Ok(data.chunks(2).map(|bytes| word([bytes[0], bytes[1]])).collect())
+}
@@ -0,0 +1,145 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{interpreter::Interpreter, transpiler::{self, Instruction}};
+
+const MAGIC: &[u8; 8] = b"BATPU2SS";
+
+/// Bumped whenever the layout of a save state changes. Files from other versions are rejected.
+pub const VERSION: u16 = 1;
+
+/// Serialises the complete state of a machine: the CPU, memory, call stack, every device and the panel.
+///
+/// The file starts with a magic number, the format version and a hash of the program, followed by the state of the
+/// interpreter and then the I/O bus. All numbers are little-endian.
+pub fn save(interpreter: &Interpreter) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    state.bytes(MAGIC);
+    state.u16(VERSION);
+    state.u64(program_hash(interpreter.instructions()));
+    interpreter.save_state(&mut state);
+    state.into_bytes()
+}
+
+/// Restores a state written by [`save`]. It must have been saved from the same program.
+pub fn load(interpreter: &mut Interpreter, data: &[u8]) -> Result<()> {
+    let mut state = StateReader::new(data);
+    if state.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+        bail!("Not a save state");
+    }
+
+    let version = state.u16()?;
+    if version != VERSION {
+        bail!("Save state is version {version}, but only version {VERSION} is supported");
+    }
+
+    if state.u64()? != program_hash(interpreter.instructions()) {
+        bail!("Save state was made with a different program");
+    }
+
+    interpreter.load_state(&mut state)?;
+    state.finish()
+}
+
+pub fn save_file(interpreter: &Interpreter, path: &Path) -> Result<()> {
+    fs::write(path, save(interpreter)).with_context(|| format!("Failed to write save state {}", path.display()))
+}
+
+pub fn load_file(interpreter: &mut Interpreter, path: &Path) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Failed to read save state {}", path.display()))?;
+    load(interpreter, &data).with_context(|| format!("Failed to load save state {}", path.display()))
+}
+
+/// FNV-1a over the program's machine code, so states can't be loaded into a program they don't belong to.
+/// Only programs built by hand can fail to encode, and those all share the hash of an empty program.
+fn program_hash(instructions: &[Instruction]) -> u64 {
+    transpiler::encode(instructions).unwrap_or_default().iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Builds the body of a save state
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back what a [`StateWriter`] wrote, failing if the data runs out
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("Save state is truncated");
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Checks that everything has been read
+    pub fn finish(&self) -> Result<()> {
+        if !self.data.is_empty() {
+            bail!("Save state has {} unexpected bytes at the end", self.data.len());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::interface::PixelBuffer;
+
+pub type Colour = [u8; 3];
+
+/// Largest screenshot scale, which keeps images at 4096 by 4096 pixels
+pub const MAX_SCALE: u32 = 128;
+
+/// How the screen is rendered to an image
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenshotOptions {
+    /// Size of each screen pixel in image pixels
+    pub scale: u32,
+    pub on: Colour,
+    pub off: Colour,
+}
+
+/// Writes the screen to a `.png` or `.pbm` file, chosen by the extension of `path`.
+/// PBM is monochrome, so the colours only apply to PNG; lit pixels are written as white.
+pub fn save(path: &Path, screen: &PixelBuffer, options: &ScreenshotOptions) -> Result<()> {
+    check_scale(options.scale)?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase());
+    let writer = match extension.as_deref() {
+        Some("png" | "pbm") => BufWriter::new(File::create(path).with_context(|| format!("Failed to create {}", path.display()))?),
+        _ => bail!("Screenshots must be .png or .pbm files"),
+    };
+
+    let rows = scaled_rows(screen, options.scale);
+
+    match extension.as_deref() {
+        Some("png") => write_png(writer, &rows, options),
+        _ => write_pbm(writer, &rows),
+    }.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Fails unless `scale` is between 1 and [`MAX_SCALE`]
+pub fn check_scale(scale: u32) -> Result<()> {
+    ensure!((1..=MAX_SCALE).contains(&scale), "Screenshot scale {scale} is out of range, it must be 1 to {MAX_SCALE}");
+    Ok(())
+}
+
+/// The screen as image rows from top to bottom. Row 0 of the screen is the bottom row, like in `ui::draw_screen`.
+pub fn scaled_rows(screen: &PixelBuffer, scale: u32) -> Vec<Vec<bool>> {
+    let scale = scale.max(1) as usize;
+
+    screen.iter().rev()
+        .flat_map(|row| {
+            let row = row.iter().flat_map(|&pixel| [pixel].repeat(scale)).collect::<Vec<_>>();
+            vec![row; scale]
+        })
+        .collect()
+}
+
+fn write_png(writer: impl Write, rows: &[Vec<bool>], options: &ScreenshotOptions) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, rows[0].len() as u32, rows.len() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data = rows.iter()
+        .flatten()
+        .flat_map(|&pixel| if pixel { options.on } else { options.off })
+        .collect::<Vec<_>>();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_pbm(mut writer: impl Write, rows: &[Vec<bool>]) -> Result<()> {
+    writeln!(writer, "P4\n{} {}", rows[0].len(), rows.len())?;
+
+    // Binary PBM packs 8 pixels per byte, MSB first, with 1 being black
+    for row in rows {
+        let bytes = row.chunks(8)
+            .map(|chunk| chunk.iter().enumerate().fold(0u8, |byte, (i, &pixel)| byte | ((!pixel as u8) << (7 - i))))
+            .collect::<Vec<_>>();
+        writer.write_all(&bytes)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Parses an `rrggbb` hex colour, with or without a leading `#`
+pub fn parse_colour(text: &str) -> Result<Colour, String> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)
+        .ok_or_else(|| format!("`{text}` is not an rrggbb hex colour"))?;
+
+    let [_, r, g, b] = value.to_be_bytes();
+    Ok([r, g, b])
+}
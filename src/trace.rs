@@ -0,0 +1,163 @@
+use std::{collections::HashMap, fmt::Write as _, fs::File, io::{BufWriter, Write}, ops::RangeInclusive, path::Path};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    assembler,
+    interpreter::{CpuState, Interpreter, Observer},
+    transpiler::Instruction,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One line of text per instruction
+    Text,
+    /// One JSON object per instruction
+    Jsonl
+}
+
+/// Which executed instructions end up in a trace
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    /// PC ranges to trace, or every address if empty
+    pub addresses: Vec<RangeInclusive<usize>>,
+    /// Instruction numbers to trace, counting from 1
+    pub window: Option<RangeInclusive<usize>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: u16, count: usize) -> bool {
+        (self.addresses.is_empty() || self.addresses.iter().any(|range| range.contains(&(pc as usize))))
+            && self.window.as_ref().is_none_or(|window| window.contains(&count))
+    }
+}
+
+/// Logs every executed instruction with the register, flags and memory it changed.
+///
+/// Text traces have one line per instruction:
+/// `<count> <pc> <instruction> [r<n>=<value>] [Z=<0|1>] [C=<0|1>] [read|write <mem|io>[<addr>]=<value>]`,
+/// where the flags only appear when they change. JSON Lines traces have the same fields as one object per line.
+pub struct Tracer {
+    writer: BufWriter<File>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    /// First error while writing, reported when the trace is finished
+    error: Option<std::io::Error>,
+}
+
+impl Tracer {
+    pub fn create(path: &Path, format: TraceFormat, filter: TraceFilter) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            format,
+            filter,
+            error: None,
+        })
+    }
+}
+
+impl Observer for Tracer {
+    fn observe(&mut self, before: &CpuState, interpreter: &Interpreter) {
+        let count = interpreter.instruction_count;
+        if self.error.is_some() || !self.filter.matches(before.pc, count) {
+            return;
+        }
+
+        let register = destination(before.instruction).map(|reg| (reg, interpreter.registers[reg as usize]));
+        let zero = Some(interpreter.zero).filter(|&zero| zero != before.zero);
+        let carry = Some(interpreter.carry).filter(|&carry| carry != before.carry);
+        let access = match before.instruction {
+            Instruction::Lod(a, _, offset) | Instruction::Str(a, _, offset) => {
+                let addr = before.registers[a as usize].wrapping_add_signed(offset);
+                Some((matches!(before.instruction, Instruction::Str(..)), addr, interpreter.memory[addr as usize]))
+            },
+            _ => None,
+        };
+
+        let instruction = before.instruction.to_assembly(&HashMap::new());
+        let mut line = String::new();
+        match self.format {
+            TraceFormat::Text => {
+                write!(line, "{count} {:04} {instruction}", before.pc).unwrap();
+                if let Some((reg, value)) = register {
+                    write!(line, " r{reg}={value}").unwrap();
+                }
+                if let Some(zero) = zero {
+                    write!(line, " Z={}", zero as u8).unwrap();
+                }
+                if let Some(carry) = carry {
+                    write!(line, " C={}", carry as u8).unwrap();
+                }
+                if let Some((write, addr, value)) = access {
+                    let kind = if interpreter.io.is_device(addr) { "io" } else { "mem" };
+                    write!(line, " {} {kind}[{addr}]={value}", if write { "write" } else { "read" }).unwrap();
+                }
+            },
+            TraceFormat::Jsonl => {
+                write!(line, r#"{{"count":{count},"pc":{},"instruction":"{instruction}""#, before.pc).unwrap();
+                if let Some((reg, value)) = register {
+                    write!(line, r#","register":{reg},"value":{value}"#).unwrap();
+                }
+                if let Some(zero) = zero {
+                    write!(line, r#","zero":{zero}"#).unwrap();
+                }
+                if let Some(carry) = carry {
+                    write!(line, r#","carry":{carry}"#).unwrap();
+                }
+                if let Some((write, addr, value)) = access {
+                    write!(
+                        line,
+                        r#","access":{{"kind":"{}","addr":{addr},"value":{value},"io":{}}}"#,
+                        if write { "write" } else { "read" },
+                        interpreter.io.is_device(addr),
+                    ).unwrap();
+                }
+                line.push('}');
+            },
+        }
+
+        if let Err(err) = writeln!(self.writer, "{line}") {
+            self.error = Some(err);
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err).context("Failed to write trace");
+        }
+        self.writer.flush().context("Failed to write trace")
+    }
+}
+
+/// The register an instruction writes, other than the hardwired r0
+fn destination(instruction: Instruction) -> Option<u8> {
+    let reg = match instruction {
+        Instruction::Add(_, _, c)
+        | Instruction::Sub(_, _, c)
+        | Instruction::Nor(_, _, c)
+        | Instruction::And(_, _, c)
+        | Instruction::Xor(_, _, c)
+        | Instruction::Rsh(_, c)
+        | Instruction::Ldi(c, _)
+        | Instruction::Adi(c, _)
+        | Instruction::Lod(_, c, _) => c,
+        _ => return None,
+    };
+    Some(reg).filter(|&reg| reg != 0)
+}
+
+/// Parses an inclusive range written as `<start>-<end>`, `<start>-` for no end, or a single number
+pub fn parse_range(text: &str) -> Result<RangeInclusive<usize>, String> {
+    let number = |text: &str| text.parse().ok()
+        .or_else(|| assembler::parse_number(text).and_then(|n| usize::try_from(n).ok()))
+        .ok_or_else(|| format!("`{text}` is not a number"));
+
+    match text.split_once('-') {
+        Some((start, "")) => Ok(number(start)?..=usize::MAX),
+        Some((start, end)) => Ok(number(start)?..=number(end)?),
+        None => number(text).map(|n| n..=n),
+    }
+}
@@ -1,11 +1,15 @@
-use std::{collections::{HashMap, HashSet}, ops::Range};
+use std::{collections::{HashMap, HashSet}, fmt, ops::Range};
+
+use clap::ValueEnum;
+
+use crate::interpreter::{CallStackPolicy, ExecutionOptions, Fault, FaultKind, ROM_SIZE};
 
 type Register = u8;
 type Immediate = u8;
 type Address = u16;
 type Offset = i8;
 
-pub struct EncodedInstruction(u16);
+pub struct EncodedInstruction(pub u16);
 
 impl EncodedInstruction {
     pub fn read_bits(&self, range: Range<usize>) -> u16 {
@@ -51,9 +55,43 @@ impl EncodedInstruction {
     fn condition(&self) -> Condition {
         Condition::from_bits(self.read_bits(4..6))
     }
+
+    /// The bits that are set but ignored by the instruction's opcode, which [`Instruction::encode`] leaves clear
+    pub fn ignored_bits(&self) -> u16 {
+        let mask = match self.opcode() {
+            // nop, hlt, ret
+            0 | 1 | 13 => 0x0FFF,
+            // rsh has no second source register
+            7 => 0x00F0,
+            // jmp, cal
+            10 | 12 => 0x0C00,
+            _ => 0,
+        };
+        self.0 & mask
+    }
 }
 
-#[derive(Debug)]
+/// An operand that doesn't fit in its field of an encoded instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    Register(u8),
+    Address(u16),
+    Offset(i8),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::Register(reg) => write!(f, "Register `r{reg}` out of range [0, 15]"),
+            EncodeError::Address(addr) => write!(f, "Address `{addr}` out of range [0, 1023]"),
+            EncodeError::Offset(offset) => write!(f, "Offset `{offset}` out of range [-8, 7]"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Nop,
     Hlt,
@@ -97,7 +135,60 @@ impl Instruction {
         DESERIALISERS[instruction.opcode() as usize](instruction)
     }
 
-    pub fn to_nasm(&self, label_map: &HashMap<u16, String>) -> String {
+    /// Encodes the instruction as a machine code word, the inverse of [`from_instruction`](Self::from_instruction).
+    /// Bits the instruction doesn't use are left clear.
+    pub fn encode(&self) -> Result<u16, EncodeError> {
+        let reg = |reg: u8| if reg < 16 { Ok(reg as u16) } else { Err(EncodeError::Register(reg)) };
+        let addr = |addr: u16| if addr < 1024 { Ok(addr) } else { Err(EncodeError::Address(addr)) };
+        let offset = |offset: i8| if (-8..=7).contains(&offset) { Ok(offset as u16 & 0xF) } else { Err(EncodeError::Offset(offset)) };
+        let three_registers = |opcode: u16, a: u8, b: u8, c: u8| Ok(opcode << 12 | reg(a)? << 8 | reg(b)? << 4 | reg(c)?);
+
+        match *self {
+            Instruction::Nop => Ok(0),
+            Instruction::Hlt => Ok(1 << 12),
+            Instruction::Add(a, b, c) => three_registers(2, a, b, c),
+            Instruction::Sub(a, b, c) => three_registers(3, a, b, c),
+            Instruction::Nor(a, b, c) => three_registers(4, a, b, c),
+            Instruction::And(a, b, c) => three_registers(5, a, b, c),
+            Instruction::Xor(a, b, c) => three_registers(6, a, b, c),
+            Instruction::Rsh(a, c) => Ok(7 << 12 | reg(a)? << 8 | reg(c)?),
+            Instruction::Ldi(a, i) => Ok(8 << 12 | reg(a)? << 8 | i as u16),
+            Instruction::Adi(a, i) => Ok(9 << 12 | reg(a)? << 8 | i as u16),
+            Instruction::Jmp(a) => Ok(10 << 12 | addr(a)?),
+            Instruction::Brh(c, a) => Ok(11 << 12 | c.bits() << 10 | addr(a)?),
+            Instruction::Cal(a) => Ok(12 << 12 | addr(a)?),
+            Instruction::Ret => Ok(13 << 12),
+            Instruction::Lod(a, b, o) => Ok(14 << 12 | reg(a)? << 8 | reg(b)? << 4 | offset(o)?),
+            Instruction::Str(a, b, o) => Ok(15 << 12 | reg(a)? << 8 | reg(b)? << 4 | offset(o)?),
+        }
+    }
+
+    /// The instruction in the assembler's syntax, with jump targets named by `label_map` where it has them
+    pub fn to_assembly(&self, label_map: &HashMap<u16, String>) -> String {
+        let target = |addr: &u16| label_map.get(addr).cloned().unwrap_or_else(|| addr.to_string());
+
+        match self {
+            Instruction::Nop => "nop".into(),
+            Instruction::Hlt => "hlt".into(),
+            Instruction::Add(a, b, c) => format!("add r{a} r{b} r{c}"),
+            Instruction::Sub(a, b, c) => format!("sub r{a} r{b} r{c}"),
+            Instruction::Nor(a, b, c) => format!("nor r{a} r{b} r{c}"),
+            Instruction::And(a, b, c) => format!("and r{a} r{b} r{c}"),
+            Instruction::Xor(a, b, c) => format!("xor r{a} r{b} r{c}"),
+            Instruction::Rsh(a, c) => format!("rsh r{a} r{c}"),
+            Instruction::Ldi(a, i) => format!("ldi r{a} {i}"),
+            // Immediates are added modulo 256, so small negative numbers read better
+            Instruction::Adi(a, i) => format!("adi r{a} {}", *i as i8),
+            Instruction::Jmp(a) => format!("jmp {}", target(a)),
+            Instruction::Brh(c, a) => format!("brh {} {}", c.name(), target(a)),
+            Instruction::Cal(a) => format!("cal {}", target(a)),
+            Instruction::Ret => "ret".into(),
+            Instruction::Lod(a, b, o) => format!("lod r{a} r{b} {o}"),
+            Instruction::Str(a, b, o) => format!("str r{a} r{b} {o}"),
+        }
+    }
+
+    pub fn to_nasm(&self, addr: u16, label_map: &HashMap<u16, String>, options: &TranspileOptions) -> String {
         fn get_dest_str(reg: u8) -> String {
             if reg == 0 {
                 "al".into()
@@ -106,9 +197,16 @@ impl Instruction {
             }
         }
 
+        let target = options.target;
+        let check_call_stack = options.execution.call_stack_policy != CallStackPolicy::Wrap;
+        let count = if options.count_instructions { include_str!("benchmark.asm") } else { "" };
+
         match self {
             Instruction::Nop => include_str!("intrinsics/nop.asm").into(),
-            Instruction::Hlt => include_str!("intrinsics/hlt.asm").into(),
+            Instruction::Hlt => match target {
+                Target::Windows => include_str!("intrinsics/hlt.asm").into(),
+                Target::Linux => include_str!("intrinsics/linux/hlt.asm").into(),
+            },
             Instruction::Add(a, b, c) => {
                 let dest = get_dest_str(*c);
 
@@ -158,21 +256,39 @@ impl Instruction {
                 Condition::GreaterThanOrEqual =>  format!(include_str!("intrinsics/brh/ge.asm"), l = label_map[a]),
                 Condition::LessThan => format!(include_str!("intrinsics/brh/lt.asm"), l = label_map[a]),
             },
-            Instruction::Cal(a) => format!(include_str!("intrinsics/cal.asm"), l = label_map[a]),
-            Instruction::Ret => include_str!("intrinsics/ret.asm").into(),
+            Instruction::Cal(a) => {
+                let check = if check_call_stack {
+                    format!(include_str!("intrinsics/call_stack/overflow.asm"), status = Fault::status(FaultKind::CallStackOverflow, addr))
+                } else {
+                    String::new()
+                };
+                format!(include_str!("intrinsics/cal.asm"), check = check + count, l = label_map[a], r = format!("return_{addr}"))
+            },
+            Instruction::Ret => {
+                let check = if check_call_stack {
+                    format!(include_str!("intrinsics/call_stack/underflow.asm"), status = Fault::status(FaultKind::CallStackUnderflow, addr))
+                } else {
+                    String::new()
+                };
+                format!(include_str!("intrinsics/ret.asm"), check = check + count)
+            },
             Instruction::Lod(a, b, o) => {
                 let dest = get_dest_str(*b);
-                format!(include_str!("intrinsics/lod.asm"), a = a, o = o, dest = dest)
+                match target {
+                    Target::Windows => format!(include_str!("intrinsics/lod.asm"), a = a, o = o, dest = dest),
+                    Target::Linux => format!(include_str!("intrinsics/linux/lod.asm"), a = a, o = o, dest = dest),
+                }
                 // format!("\tmov r8, reg\n\tmov cl, [reg + {b}]\n\tadd cl, {o}\n\tmovzx rcx, cl\n\tmov dl, [r8 + rcx]\n\tmov {dest}, dl") "\tmov cl, [reg + {a}]\n\tadd cl, {o}\n\tmovzx rcx, cl\n\tmov dl, [r8 + rcx]\n\tmov {dest}, dl")
             },
-            Instruction::Str(a, b, o) => {
-                format!(include_str!("intrinsics/str.asm"), a = a, b = b, o = o)
+            Instruction::Str(a, b, o) => match target {
+                Target::Windows => format!(include_str!("intrinsics/str.asm"), a = a, b = b, o = o),
+                Target::Linux => format!(include_str!("intrinsics/linux/str.asm"), a = a, b = b, o = o),
             },
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Condition {
     Equal,
     NotEqual,
@@ -190,6 +306,25 @@ impl Condition {
             _ => unreachable!()
         }
     }
+
+    fn bits(&self) -> u16 {
+        match self {
+            Condition::Equal => 0b00,
+            Condition::NotEqual => 0b01,
+            Condition::GreaterThanOrEqual => 0b10,
+            Condition::LessThan => 0b11,
+        }
+    }
+
+    /// The condition's name in the assembler
+    pub fn name(&self) -> &'static str {
+        match self {
+            Condition::Equal => "eq",
+            Condition::NotEqual => "ne",
+            Condition::GreaterThanOrEqual => "ge",
+            Condition::LessThan => "lt",
+        }
+    }
 }
 
 pub fn disassemble(bin: &[u16]) -> Vec<Instruction> {
@@ -199,15 +334,46 @@ pub fn disassemble(bin: &[u16]) -> Vec<Instruction> {
         .collect()
 }
 
-pub fn parse_mc_file(src: &str) -> Vec<u16> {
-    src.lines()
-        .map(|line| u16::from_str_radix(line, 2).unwrap())
-        .collect()
+/// Turns instructions back into machine code, the reverse of [`disassemble`] for words without ignored bits
+pub fn encode(instructions: &[Instruction]) -> Result<Vec<u16>, EncodeError> {
+    instructions.iter().map(Instruction::encode).collect()
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Win64 calling convention, assembled into a DLL with golink
+    Windows,
+    /// System V calling convention, assembled into an ELF shared object with ld
+    Linux
+}
+
+impl Target {
+    pub fn host() -> Self {
+        if cfg!(target_os = "windows") {
+            Target::Windows
+        } else {
+            Target::Linux
+        }
+    }
+
+    pub fn library_extension(&self) -> &'static str {
+        match self {
+            Target::Windows => "dll",
+            Target::Linux => "so",
+        }
+    }
 }
 
-pub fn transpile(src: &str, count_instructions: bool) -> String {
-    let parsed = parse_mc_file(src);
-    let instructions = disassemble(&parsed);
+#[derive(Clone, Copy)]
+pub struct TranspileOptions {
+    pub target: Target,
+    /// Increment the instruction counter before every instruction
+    pub count_instructions: bool,
+    pub execution: ExecutionOptions,
+}
+
+pub fn transpile(program: &[u16], options: &TranspileOptions) -> String {
+    let instructions = disassemble(program);
     let labels = find_labels(&instructions);
 
     let label_map = labels.iter()
@@ -216,22 +382,51 @@ pub fn transpile(src: &str, count_instructions: bool) -> String {
         .collect::<HashMap<_, _>>();
 
 
-    let mut output = String::new();
-    for (i, instruction) in instructions.iter().enumerate() {
-        if count_instructions {
-            output += include_str!("benchmark.asm");
+    // Code is generated for the whole ROM, with `nop`s after the loaded program
+    let mut output = "_rom_start:\n".to_string();
+    for addr in 0..ROM_SIZE as u16 {
+        if let Some(label) = label_map.get(&addr) {
+            output += &format!("{label}:\n");
         }
 
-        if let Some(label) = label_map.get(&(i as u16)) {
-            output += &format!("{label}:\n");
+        let instruction = match instructions.get(addr as usize) {
+            Some(instruction) => instruction,
+            None if options.execution.strict => {
+                output += &format!(include_str!("intrinsics/past_end.asm"), status = Fault::status(FaultKind::PastEndOfProgram, addr));
+                output += "\n";
+                continue;
+            },
+            None => &Instruction::Nop,
+        };
+
+        // Instructions that fault aren't executed, so `cal` and `ret` are counted after their checks
+        if options.count_instructions && !matches!(instruction, Instruction::Cal(_) | Instruction::Ret) {
+            output += include_str!("benchmark.asm");
         }
-        output += &format!("{}\n", instruction.to_nasm(&label_map));
+
+        output += &format!("{}\n", instruction.to_nasm(addr, &label_map, options));
     }
 
-    format!("{}\n{output}\n", include_str!("asm_header.asm"))
+    // The program counter wraps around after the last address
+    output += "    jmp _rom_start\n";
+
+    let header = match options.target {
+        Target::Windows => include_str!("asm_header.asm"),
+        Target::Linux => include_str!("asm_header_linux.asm"),
+    };
+
+    format!("{header}\n{output}\n")
+}
+
+/// Names the targets found by [`find_labels`] `.label_0`, `.label_1` and so on, for programs without symbols
+pub fn generated_labels(instructions: &[Instruction]) -> Vec<(String, u16)> {
+    find_labels(instructions).into_iter()
+        .enumerate()
+        .map(|(i, addr)| (format!(".label_{i}"), addr))
+        .collect()
 }
 
-fn find_labels(instructions: &[Instruction]) -> Vec<u16> {
+pub fn find_labels(instructions: &[Instruction]) -> Vec<u16> {
     let mut labels = HashSet::new();
 
     for instruction in instructions.iter() {
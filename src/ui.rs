@@ -1,12 +1,16 @@
-use std::{io::stdout, time::Duration};
+use std::{collections::HashMap, io::stdout, path::{Path, PathBuf}, sync::Mutex, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-use crossterm::{cursor, event, execute, style::{self, Color, Print}, terminal::{self, disable_raw_mode, enable_raw_mode}};
+use crossterm::{cursor, event::{self, KeyEventKind, KeyboardEnhancementFlags}, execute, style::{self, Color, Print}, terminal::{self, disable_raw_mode, enable_raw_mode}};
 
-use crate::interface::{self, NumberDisplaySettings, PixelBuffer, NUMBER_DISPLAY, NUMBER_DISPLAY_SETTINGS};
+use batpu_emulator::{interface::{Button, CharacterLine, NumberDisplaySettings, Panel, PixelBuffer, StateRequest, CHARACTER_DISPLAY_WIDTH}, screenshot::{self, ScreenshotOptions}};
+
+use crate::keymap::{Keymap, LOAD_STATE_KEY, REWIND_KEY, SAVE_STATE_KEY, SCREENSHOT_KEY, THROTTLE_KEY};
 
 type CharPos = (u16, u16);
 
-pub fn ui_main() {
+/// Draws `panel` until Esc is pressed. Key presses drive the controller unless `keyboard_input` is false.
+/// The save state hotkeys use `state_file`, or report that the backend can't save states or rewind if it is `None`.
+pub fn ui_main(panel: &Mutex<Panel>, keymap: &Keymap, keyboard_input: bool, screenshot_options: &ScreenshotOptions, state_file: Option<&Path>) {
     enable_raw_mode().unwrap();
 
     let tick_interval = Duration::from_millis(50);
@@ -23,6 +27,15 @@ pub fn ui_main() {
         Print(include_str!("ui/framing.txt"))
     ).unwrap();
 
+    // Most terminals only report key presses, in which case releases are emulated by holding each button for a while
+    let enhanced_keyboard = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced_keyboard {
+        execute!(w, event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)).unwrap();
+    }
+    let mut releases_reported = enhanced_keyboard;
+    let mut held_buttons: HashMap<Button, Instant> = HashMap::new();
+    let mut rewind_pressed_at: Option<Instant> = None;
+
     loop {
         if let Ok(event) = event::poll(tick_interval) {
             if event {
@@ -30,35 +43,99 @@ pub fn ui_main() {
                 // println!("{:?}", event);
 
                 match event {
-                    event::Event::Key(key_event) => {
-                        if key_event.code == event::KeyCode::Esc {
-                            break;
+                    event::Event::Key(key_event) if key_event.code == event::KeyCode::Esc => {
+                        break;
+                    }
+                    event::Event::Key(key_event) if key_event.code == SCREENSHOT_KEY && key_event.kind == KeyEventKind::Press => {
+                        draw_status(origin, &take_screenshot(&panel.lock().unwrap().screen, screenshot_options));
+                    }
+                    event::Event::Key(key_event) if matches!(key_event.code, SAVE_STATE_KEY | LOAD_STATE_KEY) && key_event.kind == KeyEventKind::Press => {
+                        match state_file {
+                            Some(path) => panel.lock().unwrap().state_request = Some(match key_event.code {
+                                SAVE_STATE_KEY => StateRequest::Save(path.to_path_buf()),
+                                _ => StateRequest::Load(path.to_path_buf()),
+                            }),
+                            None => draw_status(origin, "Save states need the interpreter backend"),
+                        }
+                    }
+                    event::Event::Key(key_event) if key_event.code == THROTTLE_KEY && key_event.kind == KeyEventKind::Press => {
+                        let mut panel = panel.lock().unwrap();
+                        panel.unthrottled = !panel.unthrottled;
+                    }
+                    event::Event::Key(key_event) if key_event.code == REWIND_KEY => {
+                        if state_file.is_none() {
+                            draw_status(origin, "Rewinding needs the interpreter backend");
+                        } else if key_event.kind == KeyEventKind::Release {
+                            releases_reported = true;
+                            rewind_pressed_at = None;
+                            panel.lock().unwrap().rewinding = false;
+                        } else {
+                            rewind_pressed_at = Some(Instant::now());
+                            panel.lock().unwrap().rewinding = true;
                         }
                     }
-                    event::Event::Resize(_, y) => {
-                        if y >= 34 {
-                            origin.1 = y - 34;
+                    event::Event::Key(_) if !keyboard_input => {}
+                    event::Event::Key(key_event) => if let Some(button) = keymap.button(key_event.code) {
+                        let pressed = key_event.kind != KeyEventKind::Release;
+                        if pressed {
+                            held_buttons.insert(button, Instant::now());
+                        } else {
+                            releases_reported = true;
+                            held_buttons.remove(&button);
                         }
+                        panel.lock().unwrap().controller.set(button, pressed);
+                    }
+                    event::Event::Resize(_, y) if y >= 34 => {
+                        origin.1 = y - 34;
                     }
                     _ => {}
                 }
             }
         }
-        
-        if *interface::NUMBER_DISPLAY_DIRTY.lock().unwrap() {
-            draw_number_display(origin, *NUMBER_DISPLAY.lock().unwrap(), *NUMBER_DISPLAY_SETTINGS.lock().unwrap());
-            *interface::NUMBER_DISPLAY_DIRTY.lock().unwrap() = false;
+
+        if !releases_reported {
+            if rewind_pressed_at.is_some_and(|pressed_at| pressed_at.elapsed() >= keymap.hold) {
+                rewind_pressed_at = None;
+                panel.lock().unwrap().rewinding = false;
+            }
+            held_buttons.retain(|&button, pressed_at| {
+                let held = pressed_at.elapsed() < keymap.hold;
+                if !held {
+                    panel.lock().unwrap().controller.set(button, false);
+                }
+                held
+            });
+        }
+
+        let mut panel = panel.lock().unwrap();
+        if let Some(status) = panel.status.take() {
+            draw_status(origin, &status);
+        }
+        if panel.number_display_dirty {
+            draw_number_display(origin, panel.number_display, panel.number_display_settings);
+            panel.number_display_dirty = false;
         }
-        if *interface::CHARACTER_DISPLAY_DIRTY.lock().unwrap() {
-            draw_text_display(origin, &interface::CHARACTER_DISPLAY.lock().unwrap());
-            *interface::CHARACTER_DISPLAY_DIRTY.lock().unwrap() = false;
+        if panel.character_display_dirty {
+            draw_text_display(origin, &panel.character_display);
+            panel.character_display_dirty = false;
         }
-        if *interface::SCREEN_BUFFER_DIRTY.lock().unwrap() {
-            draw_screen(origin, &interface::SCREEN_BUFFER.lock().unwrap());
-            *interface::SCREEN_BUFFER_DIRTY.lock().unwrap() = false;
+        if panel.screen_dirty {
+            draw_screen(origin, &panel.screen);
+            panel.screen_dirty = false;
         }
     }
 
+    // Let the program carry on at full speed if it was paused for rewinding or throttled
+    {
+        let mut panel = panel.lock().unwrap();
+        panel.rewinding = false;
+        panel.unthrottled = true;
+    }
+
+    if enhanced_keyboard {
+        execute!(w, event::PopKeyboardEnhancementFlags).unwrap();
+    }
+
     execute!(w,
         style::ResetColor,
         cursor::Show,
@@ -69,6 +146,28 @@ pub fn ui_main() {
     disable_raw_mode().unwrap();
 }
 
+fn take_screenshot(screen: &PixelBuffer, options: &ScreenshotOptions) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let path = PathBuf::from(format!("screenshot_{timestamp}.png"));
+
+    match screenshot::save(&path, screen, options) {
+        Ok(()) => format!("Saved {}", path.display()),
+        Err(err) => format!("Error: {err:#}"),
+    }
+}
+
+const STATUS_POS: CharPos = (0, 33);
+
+fn draw_status(origin: CharPos, message: &str) {
+    execute!(
+        stdout(),
+        style::ResetColor,
+        cursor::MoveTo(origin.0 + STATUS_POS.0, origin.1 + STATUS_POS.1),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+        Print(message)
+    ).unwrap();
+}
+
 const NUMBER_DISPLAY_POS: CharPos = (75, 1);
 
 fn draw_number_display(origin: CharPos, value: u8, settings: NumberDisplaySettings) {
@@ -123,20 +222,24 @@ fn u8_to_decimal_array(mut n: u8) -> [u8; 3] {
 
 const TEXT_DISPLAY_POS: CharPos = (72, 8);
 
-fn draw_text_display(origin: CharPos, data: &str) {
+fn draw_text_display(origin: CharPos, data: &CharacterLine) {
+    // Each character cell is two columns wide so the display fills its frame
+    let text = (0..CHARACTER_DISPLAY_WIDTH)
+        .map(|i| format!("{} ", data.get(i).copied().unwrap_or(' ')))
+        .collect::<String>();
+
     execute!(
         stdout(),
         cursor::MoveTo(origin.0 + TEXT_DISPLAY_POS.0, origin.1 + TEXT_DISPLAY_POS.1),
-        Print(format!("{data: <20}"))
+        Print(text)
     ).unwrap();
 }
 
 const SCREEN_POS: CharPos = (1, 1);
 
 fn draw_screen(origin: CharPos, data: &PixelBuffer) {
-    for y in 0..32 {
-        for x in 0..32 {
-            let pixel = data[31 - y][x];
+    for (y, row) in data.iter().rev().enumerate() {
+        for (x, &pixel) in row.iter().enumerate() {
             let x = origin.0 + SCREEN_POS.0 + x as u16 * 2;
             let y = origin.1 + SCREEN_POS.1 + y as u16;
             
@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use batpu_emulator::{
+    assembler,
+    disasm,
+    transpiler::{Condition, EncodeError, EncodedInstruction, Instruction},
+};
+
+fn decode(word: u16) -> Instruction {
+    Instruction::from_instruction(EncodedInstruction(word))
+}
+
+#[test]
+fn every_encoding_round_trips() {
+    for word in 0..=u16::MAX {
+        let ignored = EncodedInstruction(word).ignored_bits();
+        let instruction = decode(word);
+        let encoded = instruction.encode().unwrap_or_else(|err| panic!("{word:016b} decoded to {instruction:?}: {err}"));
+
+        assert_eq!(encoded, word & !ignored, "{word:016b} decoded to {instruction:?}");
+        assert_eq!(decode(encoded), instruction);
+    }
+}
+
+#[test]
+fn ignored_bits_are_flagged() {
+    let canonical = (0..=u16::MAX).filter(|&word| EncodedInstruction(word).ignored_bits() == 0);
+    let mut per_opcode = [0; 16];
+    for word in canonical {
+        per_opcode[word as usize >> 12] += 1;
+    }
+
+    // nop, hlt and ret have no operands, rsh has no second source and jmp and cal only use 10 address bits
+    assert_eq!(per_opcode, [1, 1, 4096, 4096, 4096, 4096, 4096, 256, 4096, 4096, 1024, 4096, 1024, 1, 4096, 4096]);
+
+    assert_eq!(EncodedInstruction(0x0123).ignored_bits(), 0x0123);
+    assert_eq!(EncodedInstruction(0x1FFF).ignored_bits(), 0x0FFF);
+    assert_eq!(EncodedInstruction(0xD800).ignored_bits(), 0x0800);
+    assert_eq!(EncodedInstruction(0x7A5B).ignored_bits(), 0x0050);
+    assert_eq!(EncodedInstruction(0xAC05).ignored_bits(), 0x0C00);
+    assert_eq!(EncodedInstruction(0xBFFF).ignored_bits(), 0);
+}
+
+#[test]
+fn encoder_matches_assembler() {
+    let cases = [
+        ("add r1 r2 r3", Instruction::Add(1, 2, 3)),
+        ("rsh r15 r4", Instruction::Rsh(15, 4)),
+        ("ldi r7 255", Instruction::Ldi(7, 255)),
+        ("adi r1 -1", Instruction::Adi(1, 255)),
+        ("jmp 1023", Instruction::Jmp(1023)),
+        ("brh lt 5", Instruction::Brh(Condition::LessThan, 5)),
+        ("cal 12", Instruction::Cal(12)),
+        ("lod r2 r3 -8", Instruction::Lod(2, 3, -8)),
+        ("str r4 r5 7", Instruction::Str(4, 5, 7)),
+    ];
+
+    for (src, instruction) in cases {
+        let assembly = assembler::assemble(src, Path::new("test.as")).unwrap();
+        assert_eq!(assembly.program, [instruction.encode().unwrap()], "{src}");
+    }
+}
+
+#[test]
+fn out_of_range_operands_are_rejected() {
+    assert_eq!(Instruction::Add(16, 0, 0).encode(), Err(EncodeError::Register(16)));
+    assert_eq!(Instruction::Rsh(0, 200).encode(), Err(EncodeError::Register(200)));
+    assert_eq!(Instruction::Ldi(16, 0).encode(), Err(EncodeError::Register(16)));
+    assert_eq!(Instruction::Jmp(1024).encode(), Err(EncodeError::Address(1024)));
+    assert_eq!(Instruction::Brh(Condition::Equal, u16::MAX).encode(), Err(EncodeError::Address(u16::MAX)));
+    assert_eq!(Instruction::Lod(0, 0, 8).encode(), Err(EncodeError::Offset(8)));
+    assert_eq!(Instruction::Str(0, 0, -9).encode(), Err(EncodeError::Offset(-9)));
+    assert_eq!(Instruction::Str(0, 0, -8).encode(), Ok(0xF008));
+}
+
+#[test]
+fn disassembly_reassembles_to_the_same_program() {
+    let program = (0..=u16::MAX).filter(|&word| EncodedInstruction(word).ignored_bits() == 0).collect::<Vec<_>>();
+
+    let listing = disasm::listing(&program);
+    assert!(!listing.contains("Warning"));
+    assert_eq!(assembler::assemble(&listing, Path::new("listing.as")).unwrap().program, program);
+}